@@ -0,0 +1,70 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use light_strip::ws2812::{ChannelOrder, Encoder, Encoding, Rgb};
+
+const LEDS: usize = 55;
+
+/// The encoder this replaced, building every byte bit by bit into a fresh `Vec`.
+fn bit_to_spi(input: bool) -> [u8; 3] {
+    if input {
+        [0xFF, 0xFE, 0x00]
+    } else {
+        [0xFE, 0x00, 0x00]
+    }
+}
+
+fn byte_to_spi(input: u8) -> [u8; 24] {
+    let mut out: Vec<u8> = Vec::new();
+    for i in 0..8 {
+        let bit = (input >> (7 - i)) & 0x01;
+        out.extend_from_slice(&bit_to_spi(bit == 1));
+    }
+    let mut output = [0u8; 24];
+    output.copy_from_slice(&out);
+    output
+}
+
+fn to_spi_data(rgb: &Rgb) -> [u8; 72] {
+    let mut out = [0u8; 72];
+    let data = rgb
+        .to_array(ChannelOrder::Grb)
+        .iter()
+        .flat_map(|x| byte_to_spi(*x))
+        .collect::<Vec<u8>>();
+    out.copy_from_slice(&data);
+    out
+}
+
+fn frame() -> Vec<Rgb> {
+    (0..LEDS)
+        .map(|i| Rgb::new(i as u8, (i * 3) as u8, 255 - i as u8))
+        .collect()
+}
+
+fn encode(c: &mut Criterion) {
+    let leds = frame();
+
+    c.bench_function("per byte vec", |b| {
+        b.iter(|| {
+            let mut buffer = Vec::new();
+            for led in black_box(&leds) {
+                buffer.extend_from_slice(&to_spi_data(led));
+            }
+            buffer
+        })
+    });
+
+    let encoder = Encoder::new(Encoding::Spi24);
+    let mut buffer = Vec::with_capacity(LEDS * 3 * Encoding::Spi24.width());
+    c.bench_function("table", |b| {
+        b.iter(|| {
+            buffer.clear();
+            for led in black_box(&leds) {
+                encoder.extend(&mut buffer, &led.to_array(ChannelOrder::Grb));
+            }
+            buffer.len()
+        })
+    });
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -1,23 +1,145 @@
+use crate::ws2812::{
+    spidev_bufsiz, Calibration, ChannelOrder, Chip, Encoding, PowerBudget, Segment,
+    WhiteExtraction, DEFAULT_MAX_TRANSFER, KELVIN_RANGE, MAX_LEDS,
+};
+use rppal::spi::{Bus, SlaveSelect};
 use std::default::Default;
 use std::fmt;
 use std::fs::File;
+use std::time::Duration;
 use uuid::Uuid;
 
+const CLOCKED_HZ: u32 = 8_000_000;
+
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub id: String,
     pub friendly_name: String,
     pub mqtt_config: MqttConfig,
+    #[serde(default)]
+    pub strip_config: StripConfig,
+    /// Every strip driven by this daemon. Empty drives just `stripConfig`.
+    #[serde(default)]
+    pub outputs: Vec<StripConfig>,
+    #[serde(default)]
+    pub state: StateConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerOn {
+    /// Comes back the way it was left.
+    #[default]
+    Restore,
+    /// Comes back off, the next ON still restores the last colour.
+    Off,
+    /// Comes back on in the default colour.
+    On,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StateConfig {
+    /// Where the lights are remembered between restarts.
+    pub path: String,
+    pub power_on: PowerOn,
+    pub default_colour: [u8; 3],
+    pub default_brightness: u8,
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MqttConfig {
     pub broker: String,
     pub port: u16,
     pub username: String,
     pub password: String,
     pub topic: String,
+    /// Where HA announces it has (re)started. Empty stops listening for it.
+    #[serde(default = "default_status_topic")]
+    pub status_topic: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_status_topic() -> String {
+    "homeassistant/status".to_string()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputType {
+    Spi,
+    Recording,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StripConfig {
+    /// Tells the lights of each output apart, only needed with more than one.
+    pub name: String,
+    pub output: OutputType,
+    pub count: usize,
+    pub bus: u8,
+    pub slave_select: u8,
+    /// Only for clocked chips. WS2812 and SK6812 timing comes from the encoding,
+    /// so setting it for them is a config error.
+    pub clock_hz: Option<u32>,
+    pub pages: usize,
+    pub channel_order: Option<ChannelOrder>,
+    pub chip: Chip,
+    pub encoding: Encoding,
+    /// Overrides the spidev bufsiz read from sysfs.
+    pub max_transfer: Option<usize>,
+    /// Lets frames of timing based chips go out in more than one transfer.
+    pub split_frames: bool,
+    pub white_extraction: WhiteExtraction,
+    pub calibration: CalibrationConfig,
+    pub dithering: bool,
+    pub power: PowerConfig,
+    /// Empty drives the whole strip as one light.
+    pub segments: Vec<Segment>,
+    /// Seconds to fade over when a command doesn't give a transition.
+    pub transition: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PowerConfig {
+    /// 0 disables limiting, the draw is still estimated.
+    pub supply_amps: f32,
+    pub channel_ma: f32,
+    pub idle_ma: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CalibrationConfig {
+    pub gamma: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+    pub white: f32,
+}
+
+/// Lowercase, with anything that isn't safe in a topic or HA object id replaced.
+/// Names are told apart by this, since it is what ends up in ids and topics.
+pub fn topic_id(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
 }
 
 pub fn load(path: &str) -> Result<Config, String> {
@@ -35,17 +157,258 @@ impl Config {
             .map_err(|e| format!("Failed to write config file: {}", e))?;
         Ok(())
     }
+
+    pub fn strips(&self) -> &[StripConfig] {
+        if self.outputs.is_empty() {
+            return std::slice::from_ref(&self.strip_config);
+        }
+        &self.outputs
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.outputs.is_empty() {
+            return self.strip_config.validate();
+        }
+
+        let strips = &self.outputs;
+        for (i, strip) in strips.iter().enumerate() {
+            if strip.name.is_empty() {
+                return Err("Outputs need a name".to_string());
+            }
+            strip
+                .validate()
+                .map_err(|e| format!("Output {}: {}", strip.name, e))?;
+            let id = topic_id(&strip.name);
+            if let Some(other) = strips[..i].iter().find(|s| topic_id(&s.name) == id) {
+                return Err(format!(
+                    "Output names {} and {} both become {}",
+                    other.name, strip.name, id
+                ));
+            }
+            let shared = strips[..i].iter().find(|s| {
+                s.output == OutputType::Spi
+                    && strip.output == OutputType::Spi
+                    && s.bus == strip.bus
+                    && s.slave_select == strip.slave_select
+            });
+            if let Some(shared) = shared {
+                return Err(format!(
+                    "Outputs {} and {} both use SPI{}.{}",
+                    shared.name, strip.name, strip.bus, strip.slave_select
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StripConfig {
+    pub fn channel_order(&self) -> ChannelOrder {
+        self.channel_order.unwrap_or(self.chip.default_order())
+    }
+
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+            .or_else(spidev_bufsiz)
+            .unwrap_or(DEFAULT_MAX_TRANSFER)
+    }
+
+    pub fn encoding(&self, max_transfer: usize) -> Encoding {
+        self.encoding
+            .resolve(self.count, self.chip.channels(), max_transfer)
+    }
+
+    pub fn spi_clock_hz(&self, encoding: Encoding) -> u32 {
+        if self.chip.is_clocked() {
+            return self.clock_hz.unwrap_or(CLOCKED_HZ);
+        }
+        encoding.clock_hz()
+    }
+
+    pub fn transition(&self) -> Duration {
+        Duration::from_secs_f32(self.transition)
+    }
+
+    pub fn segments(&self) -> Vec<Segment> {
+        if self.segments.is_empty() {
+            return vec![Segment {
+                name: "strip".to_string(),
+                start: 0,
+                length: self.count,
+                reversed: false,
+            }];
+        }
+        self.segments.clone()
+    }
+
+    fn validate_segments(&self) -> Result<(), String> {
+        let mut segments = self.segments.iter().collect::<Vec<&Segment>>();
+        segments.sort_by_key(|s| s.start);
+
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.name.is_empty() {
+                return Err("Segments need a name".to_string());
+            }
+            if segment.length == 0 || segment.end() > self.count {
+                return Err(format!("Segment {} is outside the strip", segment));
+            }
+            if let Some(next) = segments.get(i + 1) {
+                if next.start < segment.end() {
+                    return Err(format!("Segment {} overlaps {}", segment, next));
+                }
+            }
+            let id = topic_id(&segment.name);
+            if let Some(other) = segments[..i].iter().find(|s| topic_id(&s.name) == id) {
+                return Err(format!(
+                    "Segment names {} and {} both become {}",
+                    other.name, segment.name, id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.count == 0 || self.count > MAX_LEDS {
+            return Err(format!(
+                "LED count {} must be between 1 and {}",
+                self.count, MAX_LEDS
+            ));
+        }
+        if self.pages == 0 {
+            return Err("At least one page is required".to_string());
+        }
+        if self.clock_hz == Some(0) {
+            return Err("SPI clock must be greater than 0".to_string());
+        }
+        if self.clock_hz.is_some() && !self.chip.is_clocked() {
+            return Err(format!(
+                "SPI clock can't be set for {} LEDs, pick an encoding instead",
+                self.chip
+            ));
+        }
+        if self.max_transfer == Some(0) {
+            return Err("Max transfer must be greater than 0".to_string());
+        }
+        Duration::try_from_secs_f32(self.transition)
+            .map_err(|e| format!("Invalid transition {}: {}", self.transition, e))?;
+        if let WhiteExtraction::Temperature(kelvin) = self.white_extraction {
+            if !KELVIN_RANGE.contains(&kelvin) {
+                return Err(format!(
+                    "White LED temperature {} K must be between {} and {}",
+                    kelvin,
+                    KELVIN_RANGE.start(),
+                    KELVIN_RANGE.end()
+                ));
+            }
+        }
+        self.calibration.validate()?;
+        self.power.validate(self.count)?;
+        self.validate_segments()?;
+        self.spi_bus()?;
+        self.spi_slave_select()?;
+        Ok(())
+    }
+
+    pub fn spi_bus(&self) -> Result<Bus, String> {
+        match self.bus {
+            0 => Ok(Bus::Spi0),
+            1 => Ok(Bus::Spi1),
+            2 => Ok(Bus::Spi2),
+            3 => Ok(Bus::Spi3),
+            4 => Ok(Bus::Spi4),
+            5 => Ok(Bus::Spi5),
+            6 => Ok(Bus::Spi6),
+            b => Err(format!("Unknown SPI bus {}", b)),
+        }
+    }
+
+    pub fn spi_slave_select(&self) -> Result<SlaveSelect, String> {
+        match self.slave_select {
+            0 => Ok(SlaveSelect::Ss0),
+            1 => Ok(SlaveSelect::Ss1),
+            2 => Ok(SlaveSelect::Ss2),
+            3 => Ok(SlaveSelect::Ss3),
+            4 => Ok(SlaveSelect::Ss4),
+            5 => Ok(SlaveSelect::Ss5),
+            6 => Ok(SlaveSelect::Ss6),
+            7 => Ok(SlaveSelect::Ss7),
+            8 => Ok(SlaveSelect::Ss8),
+            9 => Ok(SlaveSelect::Ss9),
+            10 => Ok(SlaveSelect::Ss10),
+            11 => Ok(SlaveSelect::Ss11),
+            12 => Ok(SlaveSelect::Ss12),
+            13 => Ok(SlaveSelect::Ss13),
+            14 => Ok(SlaveSelect::Ss14),
+            15 => Ok(SlaveSelect::Ss15),
+            s => Err(format!("Unknown SPI slave select {}", s)),
+        }
+    }
+}
+
+impl CalibrationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.gamma.is_nan() || self.gamma <= 0.0 {
+            return Err(format!("Gamma {} must be greater than 0", self.gamma));
+        }
+        for scale in [self.red, self.green, self.blue, self.white] {
+            if !(0.0..=1.0).contains(&scale) {
+                return Err(format!("Channel scale {} must be between 0 and 1", scale));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        Calibration::new(self.gamma, [self.red, self.green, self.blue, self.white])
+    }
+}
+
+impl PowerConfig {
+    pub fn validate(&self, count: usize) -> Result<(), String> {
+        for (name, value) in [
+            ("Supply amps", self.supply_amps),
+            ("Channel mA", self.channel_ma),
+            ("Idle mA", self.idle_ma),
+        ] {
+            if value.is_nan() || value < 0.0 {
+                return Err(format!("{} {} must not be negative", name, value));
+            }
+        }
+        let idle_ma = self.idle_ma * count as f32;
+        if self.supply_amps > 0.0 && self.supply_amps * 1000.0 <= idle_ma {
+            return Err(format!(
+                "Supply of {} A doesn't cover the {} mA the LEDs draw when dark",
+                self.supply_amps, idle_ma
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn budget(&self) -> PowerBudget {
+        PowerBudget {
+            supply_ma: (self.supply_amps > 0.0).then_some(self.supply_amps * 1000.0),
+            channel_ma: self.channel_ma,
+            idle_ma: self.idle_ma,
+        }
+    }
 }
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Config {{ MQTT: {{ broker: {}, port: {}, username: {}, password: {} }}",
+            "Config {{ MQTT: {{ broker: {}, port: {}, username: {}, password: {} }}, Strips: [{}], State: {} }}",
             self.mqtt_config.broker,
             self.mqtt_config.port,
             self.mqtt_config.username,
             self.mqtt_config.password,
+            self.strips()
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            self.state,
         )
     }
 }
@@ -54,8 +417,70 @@ impl fmt::Display for MqttConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "MqttConfig {{ broker: {}, port: {}, username: {}, password: {} }}",
-            self.broker, self.port, self.username, self.password
+            "MqttConfig {{ broker: {}, port: {}, username: {}, password: {}, status_topic: {}, discovery_prefix: {} }}",
+            self.broker,
+            self.port,
+            self.username,
+            self.password,
+            self.status_topic,
+            self.discovery_prefix
+        )
+    }
+}
+
+impl fmt::Display for StripConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "StripConfig {{ name: {}, output: {:?}, count: {}, bus: {}, slave_select: {}, clock_hz: {:?}, pages: {}, channel_order: {:?}, chip: {}, encoding: {}, max_transfer: {:?}, split_frames: {}, white_extraction: {:?}, calibration: {}, dithering: {}, power: {}, segments: {}, transition: {} }}",
+            self.name,
+            self.output,
+            self.count,
+            self.bus,
+            self.slave_select,
+            self.clock_hz,
+            self.pages,
+            self.channel_order,
+            self.chip,
+            self.encoding,
+            self.max_transfer,
+            self.split_frames,
+            self.white_extraction,
+            self.calibration,
+            self.dithering,
+            self.power,
+            self.segments.len(),
+            self.transition
+        )
+    }
+}
+
+impl fmt::Display for StateConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "StateConfig {{ path: {}, power_on: {:?}, default_colour: {:?}, default_brightness: {} }}",
+            self.path, self.power_on, self.default_colour, self.default_brightness
+        )
+    }
+}
+
+impl fmt::Display for CalibrationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CalibrationConfig {{ gamma: {}, red: {}, green: {}, blue: {}, white: {} }}",
+            self.gamma, self.red, self.green, self.blue, self.white
+        )
+    }
+}
+
+impl fmt::Display for PowerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PowerConfig {{ supply_amps: {}, channel_ma: {}, idle_ma: {} }}",
+            self.supply_amps, self.channel_ma, self.idle_ma
         )
     }
 }
@@ -63,9 +488,12 @@ impl fmt::Display for MqttConfig {
 impl Default for Config {
     fn default() -> Self {
         Config {
-            id: format!("card-monitor_{}", Uuid::new_v4()),
-            friendly_name: "Card Monitor".to_string(),
+            id: format!("light-strip_{}", Uuid::new_v4()),
+            friendly_name: "Light Strip".to_string(),
             mqtt_config: MqttConfig::default(),
+            strip_config: StripConfig::default(),
+            outputs: Vec::new(),
+            state: StateConfig::default(),
         }
     }
 }
@@ -78,6 +506,124 @@ impl Default for MqttConfig {
             username: "username".to_string(),
             password: "password".to_string(),
             topic: "bitbrain/light_strip".to_string(),
+            status_topic: default_status_topic(),
+            discovery_prefix: default_discovery_prefix(),
+        }
+    }
+}
+
+impl Default for StripConfig {
+    fn default() -> Self {
+        StripConfig {
+            name: String::new(),
+            output: OutputType::Spi,
+            count: 55,
+            bus: 0,
+            slave_select: 0,
+            clock_hz: None,
+            pages: 2,
+            channel_order: None,
+            chip: Chip::default(),
+            encoding: Encoding::default(),
+            max_transfer: None,
+            split_frames: false,
+            white_extraction: WhiteExtraction::default(),
+            calibration: CalibrationConfig::default(),
+            dithering: false,
+            power: PowerConfig::default(),
+            segments: Vec::new(),
+            transition: 0.0,
+        }
+    }
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            path: "state.json".to_string(),
+            power_on: PowerOn::default(),
+            default_colour: [255, 255, 255],
+            default_brightness: 255,
+        }
+    }
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        CalibrationConfig {
+            gamma: 1.0,
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0,
+            white: 1.0,
+        }
+    }
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        let budget = PowerBudget::default();
+        PowerConfig {
+            supply_amps: 0.0,
+            channel_ma: budget.channel_ma,
+            idle_ma: budget.idle_ma,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(name: &str, start: usize) -> Segment {
+        Segment {
+            name: name.to_string(),
+            start,
+            length: 10,
+            reversed: false,
+        }
+    }
+
+    #[test]
+    fn segment_names_must_differ_as_topics() {
+        let mut strip = StripConfig {
+            segments: vec![segment("Left", 0), segment("Kitchen A", 10)],
+            ..StripConfig::default()
+        };
+        assert!(strip.validate().is_ok());
+
+        strip.segments.push(segment("left", 20));
+        assert!(strip.validate().is_err());
+
+        strip.segments[2] = segment("kitchen_a", 20);
+        assert!(strip.validate().is_err());
+    }
+
+    #[test]
+    fn output_names_must_differ_as_topics() {
+        let output = |name: &str, slave_select| StripConfig {
+            name: name.to_string(),
+            slave_select,
+            ..StripConfig::default()
+        };
+        let mut config = Config {
+            outputs: vec![output("Left", 0), output("Right", 1)],
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.outputs[1] = output("left", 1);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn transition_must_be_a_duration() {
+        for transition in [-1.0, f32::NAN, f32::INFINITY, 1e30] {
+            let strip = StripConfig {
+                transition,
+                ..StripConfig::default()
+            };
+            assert!(strip.validate().is_err(), "{}", transition);
         }
     }
 }
@@ -1,3 +1,4 @@
 mod availability;
 mod device;
 pub mod mqtt;
+pub mod sensor;
@@ -1,14 +1,18 @@
 use super::availability::Availability;
 use super::device::Device;
+use super::sensor::Sensor;
+use crate::config::{topic_id, Config};
+use crate::ws2812::{MAX_MIREDS, MIN_MIREDS};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use std::{fmt, str::FromStr};
 
 const MANUFACTURER: &str = "bitbrain";
 const MODEL: &str = "lightstrip";
 const SW_VERSION: &str = "3.2.0";
-const NAME: &str = "Light";
 const HW_VERSION: &str = "1.0.0";
-const UNIQUE_ID: &str = "light-strip-1234";
+const FLASH_TIME_SHORT: u64 = 2;
+const FLASH_TIME_LONG: u64 = 10;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LightStripMqtt {
@@ -19,27 +23,38 @@ pub struct LightStripMqtt {
     pub state_topic: String,
     pub command_topic: String,
     brightness: bool,
-    rgb: bool,
-    color_temp: bool,
+    color_mode: bool,
+    pub supported_color_modes: Vec<String>,
+    min_mireds: u16,
+    max_mireds: u16,
     effect: bool,
     pub effect_list: Vec<String>,
+    flash_time_short: u64,
+    flash_time_long: u64,
     schema: String,
     optimistic: bool,
     icon: String,
     retain: bool,
+    #[serde(skip)]
+    base_topic: String,
+    #[serde(skip)]
+    discovery_prefix: String,
 }
 
-impl Default for LightStripMqtt {
-    fn default() -> Self {
-        let base_topic = Self::static_base_topic();
+impl LightStripMqtt {
+    /// The device's main light. Topics live under `{topic}/{id}` so several devices
+    /// can share a broker and a base topic.
+    pub fn new(config: &Config) -> LightStripMqtt {
+        let unique_id = topic_id(&config.id);
+        let base_topic = format!("{}/{}", config.mqtt_config.topic, unique_id);
         LightStripMqtt {
-            name: NAME.to_string(),
-            unique_id: UNIQUE_ID.to_string(),
+            name: config.friendly_name.clone(),
+            unique_id: unique_id.clone(),
             device: Device {
-                identifiers: vec![UNIQUE_ID.to_string()],
+                identifiers: vec![unique_id],
                 manufacturer: MANUFACTURER.to_string(),
                 model: MODEL.to_string(),
-                name: NAME.to_string(),
+                name: config.friendly_name.clone(),
                 sw_version: SW_VERSION.to_string(),
                 hw_version: HW_VERSION.to_string(),
             },
@@ -47,27 +62,41 @@ impl Default for LightStripMqtt {
             state_topic: format!("{}/state", &base_topic),
             command_topic: format!("{}/set", &base_topic),
             brightness: true,
-            rgb: true,
-            color_temp: true,
+            color_mode: true,
+            supported_color_modes: vec![
+                "rgb".to_string(),
+                "hs".to_string(),
+                "xy".to_string(),
+                "color_temp".to_string(),
+            ],
+            min_mireds: MIN_MIREDS,
+            max_mireds: MAX_MIREDS,
             effect: true,
-            effect_list: vec!["test".to_string(), "test2".to_string()],
+            effect_list: Vec::new(),
+            flash_time_short: FLASH_TIME_SHORT,
+            flash_time_long: FLASH_TIME_LONG,
             schema: "json".to_string(),
             optimistic: false,
             icon: "mdi:lightbulb".to_string(),
             retain: true,
+            base_topic,
+            discovery_prefix: config.mqtt_config.discovery_prefix.clone(),
         }
     }
-}
 
-impl LightStripMqtt {
-    fn _base_topic(&self) -> String {
-        format!("{}/lightstrip/{}", MANUFACTURER, self.unique_id)
-    }
-    fn static_base_topic() -> String {
-        format!("{}/lightstrip/{}", MANUFACTURER, UNIQUE_ID)
+    fn base_topic(&self) -> String {
+        self.base_topic.clone()
     }
     fn ha_discovery_topic(&self) -> String {
-        format!("homeassistant/light/{}/config", self.unique_id)
+        format!("{}/light/{}/config", self.discovery_prefix, self.unique_id)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn unique_id(&self) -> &str {
+        &self.unique_id
     }
 
     pub fn discovery_message(&self) -> (String, String) {
@@ -77,12 +106,56 @@ impl LightStripMqtt {
         )
     }
 
+    /// A light of its own for one output or segment, on the same device.
+    pub fn child(&self, part: &str) -> LightStripMqtt {
+        let id = topic_id(part);
+        let base_topic = format!("{}/{}", self.base_topic(), id);
+
+        LightStripMqtt {
+            name: format!("{} {}", self.name, part),
+            unique_id: format!("{}-{}", self.unique_id, id),
+            state_topic: format!("{}/state", base_topic),
+            command_topic: format!("{}/set", base_topic),
+            base_topic,
+            ..self.clone()
+        }
+    }
+
+    pub fn flash_time(&self, flash: Flash) -> Duration {
+        match flash {
+            Flash::Short => Duration::from_secs(self.flash_time_short),
+            Flash::Long => Duration::from_secs(self.flash_time_long),
+        }
+    }
+
+    pub fn current_sensor(&self) -> Sensor {
+        Sensor::current(
+            &self.name,
+            &self.unique_id,
+            &self.base_topic(),
+            self.device.clone(),
+            self.availability.clone(),
+            &self.discovery_prefix,
+        )
+    }
+
+    pub fn reconnect_sensor(&self) -> Sensor {
+        Sensor::reconnects(
+            &self.name,
+            &self.unique_id,
+            &self.base_topic(),
+            self.device.clone(),
+            self.availability.clone(),
+            &self.discovery_prefix,
+        )
+    }
+
     pub fn set_online(&self) -> (String, String) {
         self.availability.set_online()
     }
 
-    pub fn _set_offline(&self) -> (String, String) {
-        self.availability._set_offline()
+    pub fn set_offline(&self) -> (String, String) {
+        self.availability.set_offline()
     }
 
     pub fn _set_color(&self, r: u8, g: u8, b: u8) -> (String, String) {
@@ -123,73 +196,258 @@ impl LightStripMqtt {
         (topic, payload)
     }
 }
+#[derive(Debug, Clone, PartialEq)]
+pub enum Colour {
+    Rgb(u8, u8, u8),
+    Rgbw(u8, u8, u8, u8),
+    /// Hue in degrees and saturation in percent, as Home Assistant sends them.
+    Hs(f32, f32),
+    Xy(f32, f32),
+}
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum StripMode {
-    On,
-    Colour(u8, u8, u8),
-    Effect(String),
-    Brightness(u8),
-    Off,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flash {
+    Short,
+    Long,
 }
 
-impl FromStr for StripMode {
-    type Err = String;
+/// Everything a Home Assistant JSON schema command can ask for, applied together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub on: bool,
+    pub brightness: Option<u8>,
+    pub colour: Option<Colour>,
+    pub color_temp: Option<u16>,
+    pub effect: Option<String>,
+    pub transition: Option<Duration>,
+    pub flash: Option<Flash>,
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let message = serde_json::from_str::<serde_json::Value>(s).map_err(|e| e.to_string())?;
-
-        let state = message["state"]
-            .as_str()
-            .ok_or("No state found".to_string())?;
-        let brightness = message["brightness"].as_u64();
-        let color = message["color"].as_object();
-        let effect = message["effect"].as_str();
-        let color_temp = message["color_temp"].as_u64();
-
-        if "OFF" == state.to_uppercase().as_str() {
-            return Ok(StripMode::Off);
-        }
-        if "ON" != state.to_uppercase().as_str() {
-            return Err("Unknown state".to_string());
-        }
+#[derive(Deserialize)]
+struct CommandMessage {
+    state: String,
+    brightness: Option<u8>,
+    color: Option<ColourMessage>,
+    color_temp: Option<u16>,
+    effect: Option<String>,
+    transition: Option<f64>,
+    flash: Option<String>,
+}
 
-        if let Some(color) = color {
-            let r = color["r"].as_u64().unwrap() as u8;
-            let g = color["g"].as_u64().unwrap() as u8;
-            let b = color["b"].as_u64().unwrap() as u8;
-            return Ok(StripMode::Colour(r, g, b));
-        }
+#[derive(Deserialize)]
+struct ColourMessage {
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    w: Option<u8>,
+    h: Option<f32>,
+    s: Option<f32>,
+    x: Option<f32>,
+    y: Option<f32>,
+}
 
-        if let Some(effect) = effect {
-            return Ok(StripMode::Effect(effect.to_string()));
-        }
+impl TryFrom<ColourMessage> for Colour {
+    type Error = String;
 
-        if let Some(brightness) = brightness {
-            return Ok(StripMode::Brightness(brightness as u8));
+    fn try_from(c: ColourMessage) -> Result<Self, Self::Error> {
+        match c {
+            ColourMessage {
+                r: Some(r),
+                g: Some(g),
+                b: Some(b),
+                w,
+                ..
+            } => Ok(match w {
+                Some(w) => Colour::Rgbw(r, g, b, w),
+                None => Colour::Rgb(r, g, b),
+            }),
+            ColourMessage {
+                h: Some(h),
+                s: Some(s),
+                ..
+            } => {
+                if !(0.0..=360.0).contains(&h) || !(0.0..=100.0).contains(&s) {
+                    return Err(format!("Hue {} or saturation {} is out of range", h, s));
+                }
+                Ok(Colour::Hs(h, s))
+            }
+            ColourMessage {
+                x: Some(x),
+                y: Some(y),
+                ..
+            } => {
+                // y is a divisor when going back to XYZ.
+                if !(0.0..=1.0).contains(&x) || y <= 0.0 || y > 1.0 {
+                    return Err(format!("Colour x {} or y {} is out of range", x, y));
+                }
+                Ok(Colour::Xy(x, y))
+            }
+            _ => Err("Colour needs r, g and b, h and s, or x and y".to_string()),
         }
+    }
+}
 
-        if let Some(mired) = color_temp {
-            let kelvin = 1000000 / mired as i64;
-            let rgb: colortemp::RGB = colortemp::temp_to_rgb(kelvin);
-            let (r, g, b) = (rgb.r as u8, rgb.g as u8, rgb.b as u8);
-            return Ok(StripMode::Colour(r, g, b));
-        }
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let message = serde_json::from_str::<CommandMessage>(s).map_err(|e| e.to_string())?;
+
+        let on = match message.state.to_uppercase().as_str() {
+            "ON" => true,
+            "OFF" => false,
+            state => return Err(format!("Unknown state {}", state)),
+        };
+        let transition = message
+            .transition
+            .map(|t| {
+                Duration::try_from_secs_f64(t)
+                    .map_err(|e| format!("Invalid transition {}: {}", t, e))
+            })
+            .transpose()?;
+        let flash = match message.flash.as_deref() {
+            None => None,
+            Some("short") => Some(Flash::Short),
+            Some("long") => Some(Flash::Long),
+            Some(flash) => return Err(format!("Unknown flash {}", flash)),
+        };
 
-        Ok(StripMode::On)
+        Ok(Command {
+            on,
+            brightness: message.brightness,
+            colour: message.color.map(Colour::try_from).transpose()?,
+            color_temp: message.color_temp,
+            effect: message.effect,
+            transition,
+            flash,
+        })
     }
 }
 
-impl fmt::Display for StripMode {
+impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            StripMode::On => write!(f, "ON"),
-            StripMode::Off => write!(f, "OFF"),
-            StripMode::Colour(r, g, b) => {
-                write!(f, "ON: Colour: r: {}, g: {}, b: {}", r, g, b)
+        write!(f, "{}", if self.on { "ON" } else { "OFF" })?;
+        if let Some(brightness) = self.brightness {
+            write!(f, ", Brightness: {}", brightness)?;
+        }
+        match &self.colour {
+            Some(Colour::Rgb(r, g, b)) => write!(f, ", Colour: r: {}, g: {}, b: {}", r, g, b)?,
+            Some(Colour::Rgbw(r, g, b, w)) => {
+                write!(f, ", Colour: r: {}, g: {}, b: {}, w: {}", r, g, b, w)?
             }
-            StripMode::Effect(effect) => write!(f, "ON: Effect: {}", effect),
-            StripMode::Brightness(brightness) => write!(f, "ON: Brightness: {}", brightness),
+            Some(Colour::Hs(h, s)) => write!(f, ", Colour: h: {}, s: {}", h, s)?,
+            Some(Colour::Xy(x, y)) => write!(f, ", Colour: x: {}, y: {}", x, y)?,
+            None => {}
+        }
+        if let Some(mireds) = self.color_temp {
+            write!(f, ", Temperature: {} mireds", mireds)?;
+        }
+        if let Some(effect) = &self.effect {
+            write!(f, ", Effect: {}", effect)?;
+        }
+        if let Some(transition) = self.transition {
+            write!(f, ", Transition: {:?}", transition)?;
+        }
+        if let Some(flash) = self.flash {
+            write!(f, ", Flash: {:?}", flash)?;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Command, String> {
+        Command::from_str(s)
+    }
+
+    #[test]
+    fn parses_every_field_together() {
+        let command = parse(
+            r#"{"state":"ON","brightness":80,"color":{"r":255,"g":128,"b":0},"effect":"Rainbow","transition":1.5,"flash":"short","color_temp":300}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            command,
+            Command {
+                on: true,
+                brightness: Some(80),
+                colour: Some(Colour::Rgb(255, 128, 0)),
+                color_temp: Some(300),
+                effect: Some("Rainbow".to_string()),
+                transition: Some(Duration::from_millis(1500)),
+                flash: Some(Flash::Short),
+            }
+        );
+
+        let command = parse(r#"{"state":"off"}"#).unwrap();
+        assert!(!command.on);
+        assert_eq!(command.colour, None);
+    }
+
+    #[test]
+    fn white_makes_rgb_rgbw() {
+        let colour =
+            |c: &str| parse(&format!(r#"{{"state":"ON","color":{}}}"#, c)).map(|c| c.colour);
+        assert_eq!(
+            colour(r#"{"r":1,"g":2,"b":3}"#),
+            Ok(Some(Colour::Rgb(1, 2, 3)))
+        );
+        assert_eq!(
+            colour(r#"{"r":1,"g":2,"b":3,"w":4}"#),
+            Ok(Some(Colour::Rgbw(1, 2, 3, 4)))
+        );
+    }
+
+    #[test]
+    fn checks_hs_and_xy_ranges() {
+        let colour =
+            |c: &str| parse(&format!(r#"{{"state":"ON","color":{}}}"#, c)).map(|c| c.colour);
+        assert_eq!(
+            colour(r#"{"h":180.0,"s":50.0}"#),
+            Ok(Some(Colour::Hs(180.0, 50.0)))
+        );
+        assert!(colour(r#"{"h":361.0,"s":50.0}"#).is_err());
+        assert!(colour(r#"{"h":180.0,"s":101.0}"#).is_err());
+
+        assert_eq!(
+            colour(r#"{"x":0.3,"y":0.4}"#),
+            Ok(Some(Colour::Xy(0.3, 0.4)))
+        );
+        assert!(colour(r#"{"x":1.1,"y":0.4}"#).is_err());
+        assert!(colour(r#"{"x":0.3,"y":-0.1}"#).is_err());
+        assert!(colour(r#"{"x":0.0,"y":0.0}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_partial_colours() {
+        assert!(parse(r#"{"state":"ON","color":{"r":1}}"#).is_err());
+        assert!(parse(r#"{"state":"ON","color":{"h":1.0}}"#).is_err());
+        assert!(parse(r#"{"state":"ON","color":{}}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_state_and_flash() {
+        assert!(parse(r#"{"state":"DIM"}"#).is_err());
+        assert!(parse(r#"{"brightness":10}"#).is_err());
+        assert!(parse(r#"{"state":"ON","flash":"medium"}"#).is_err());
+        assert_eq!(
+            parse(r#"{"state":"ON","flash":"long"}"#).map(|c| c.flash),
+            Ok(Some(Flash::Long))
+        );
+    }
+
+    #[test]
+    fn rejects_transitions_that_are_not_durations() {
+        assert!(parse(r#"{"state":"ON","transition":-1}"#).is_err());
+        assert!(parse(r#"{"state":"ON","transition":1e20}"#).is_err());
+        assert!(parse(r#"{"state":"ON","transition":1e400}"#).is_err());
+        assert!(parse(r#"{"state":"ON","transition":"soon"}"#).is_err());
+        assert_eq!(
+            parse(r#"{"state":"ON","transition":0}"#).map(|c| c.transition),
+            Ok(Some(Duration::ZERO))
+        );
     }
 }
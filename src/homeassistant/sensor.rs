@@ -0,0 +1,100 @@
+use super::availability::Availability;
+use super::device::Device;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sensor {
+    name: String,
+    unique_id: String,
+    device: Device,
+    availability: Availability,
+    pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+    state_class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>,
+    value_template: String,
+    icon: String,
+    #[serde(skip)]
+    discovery_prefix: String,
+}
+
+impl Sensor {
+    pub fn current(
+        name: &str,
+        unique_id: &str,
+        base_topic: &str,
+        device: Device,
+        availability: Availability,
+        discovery_prefix: &str,
+    ) -> Self {
+        Sensor {
+            name: format!("{} Current", name),
+            unique_id: format!("{}-current", unique_id),
+            device,
+            availability,
+            state_topic: format!("{}/current", base_topic),
+            device_class: Some("current".to_string()),
+            state_class: "measurement".to_string(),
+            unit_of_measurement: Some("mA".to_string()),
+            entity_category: None,
+            value_template: "{{ value_json.value }}".to_string(),
+            icon: "mdi:current-dc".to_string(),
+            discovery_prefix: discovery_prefix.to_string(),
+        }
+    }
+
+    /// How many times the broker connection has been lost and made again.
+    pub fn reconnects(
+        name: &str,
+        unique_id: &str,
+        base_topic: &str,
+        device: Device,
+        availability: Availability,
+        discovery_prefix: &str,
+    ) -> Self {
+        Sensor {
+            name: format!("{} Reconnects", name),
+            unique_id: format!("{}-reconnects", unique_id),
+            device,
+            availability,
+            state_topic: format!("{}/reconnects", base_topic),
+            device_class: None,
+            state_class: "total_increasing".to_string(),
+            unit_of_measurement: None,
+            entity_category: Some("diagnostic".to_string()),
+            value_template: "{{ value_json.value }}".to_string(),
+            icon: "mdi:lan-connect".to_string(),
+            discovery_prefix: discovery_prefix.to_string(),
+        }
+    }
+
+    fn ha_discovery_topic(&self) -> String {
+        format!("{}/sensor/{}/config", self.discovery_prefix, self.unique_id)
+    }
+
+    pub fn discovery_message(&self) -> (String, String) {
+        (
+            self.ha_discovery_topic(),
+            serde_json::to_string_pretty(&self).unwrap(),
+        )
+    }
+
+    pub fn state_message(&self, value: f32) -> (String, String) {
+        let payload = format!("{{\"value\": {:.0}}}", value);
+        let topic = self.state_topic.clone();
+
+        (topic, payload)
+    }
+}
+
+impl Display for Sensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = serde_json::to_string_pretty(&self).unwrap();
+        write!(f, "{}", s)
+    }
+}
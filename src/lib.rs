@@ -0,0 +1,2 @@
+//! The LED drivers, a library of their own so benches and tests can link against them.
+pub mod ws2812;
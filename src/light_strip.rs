@@ -1,27 +1,56 @@
-use crate::config;
-use crate::homeassistant::mqtt::{LightStripMqtt, StripMode};
-use crate::ws2812::{MyStrip, Strip};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use crate::config::{self, PowerOn};
+use crate::homeassistant::mqtt::{Colour, Command, LightStripMqtt};
+use crate::homeassistant::sensor::Sensor;
+use crate::state::StateFile;
+use crate::ws2812::{MyStrip, SegmentedStrip, Strip};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, SubscribeFilter,
+};
 use smart_led_effects::strip;
-use std::default::Default;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
-use tokio::task;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::{self, JoinHandle};
 use tokio::time::sleep;
 
-const COUNT: usize = 55;
 const UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+const CURRENT_INTERVAL: Duration = Duration::from_secs(5);
+/// systemd waits 90 s before killing us, don't get anywhere near that.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What the MQTT event loop hands to the render loop.
+enum MqttEvent {
+    Connected,
+    Disconnected,
+    /// Home Assistant restarted and needs discovery and state again.
+    HomeAssistantOnline,
+    Command(usize, Command),
+}
 
 pub struct LightStrip {
     mqtt_options: MqttOptions,
-    stop: AtomicBool,
-    ha: LightStripMqtt,
-    strip: MyStrip,
+    status_topic: Option<String>,
+    stop: Arc<AtomicBool>,
+    ha: Vec<LightStripMqtt>,
+    /// The output and segment behind each light in `ha`.
+    lights: Vec<(usize, usize)>,
+    current_sensors: Vec<Sensor>,
+    reconnect_sensor: Sensor,
+    strips: Vec<SegmentedStrip>,
+    state_file: StateFile,
 }
 
 impl LightStrip {
-    pub fn new(config: &config::Config, ha: Option<LightStripMqtt>, strip: Strip) -> LightStrip {
+    pub fn new(
+        config: &config::Config,
+        ha: Option<LightStripMqtt>,
+        strips: Vec<Strip>,
+    ) -> LightStrip {
         let mut mqtt_options = MqttOptions::new(
             &config.id,
             &config.mqtt_config.broker,
@@ -36,80 +65,307 @@ impl LightStrip {
             config.mqtt_config.port
         );
 
-        let mut ha = match ha {
-            Some(ha) => ha,
-            None => LightStripMqtt::default(),
-        };
+        let mut device = ha.unwrap_or_else(|| LightStripMqtt::new(config));
+        device.effect_list = strip::list();
 
-        ha.effect_list = strip::list();
+        // Every light shares the device's availability topic.
+        let (topic, payload) = device.set_offline();
+        mqtt_options.set_last_will(LastWill::new(topic, payload, QoS::AtLeastOnce, true));
 
-        LightStrip {
+        let mut lights = Vec::new();
+        let mut light_ha = Vec::new();
+        let mut current_sensors = Vec::new();
+        let mut segmented = Vec::new();
+        for (i, (strip_config, strip)) in config.strips().iter().zip(strips).enumerate() {
+            let mut ha = if config.outputs.is_empty() {
+                device.clone()
+            } else {
+                device.child(&strip_config.name)
+            };
+            if strip.has_white() {
+                ha.supported_color_modes.push("rgbw".to_string());
+            }
+            current_sensors.push(ha.current_sensor());
+
+            let segments = strip_config.segments();
+            if strip_config.segments.is_empty() {
+                lights.push((i, 0));
+                light_ha.push(ha);
+            } else {
+                for (j, segment) in segments.iter().enumerate() {
+                    lights.push((i, j));
+                    light_ha.push(ha.child(&segment.name));
+                }
+            }
+            segmented.push(SegmentedStrip::new(
+                strip,
+                segments,
+                strip_config.white_extraction,
+                strip_config.transition(),
+            ));
+        }
+
+        let mut light_strip = LightStrip {
             mqtt_options,
-            stop: AtomicBool::new(false),
-            ha,
-            strip: MyStrip::new(COUNT, strip),
+            status_topic: Some(config.mqtt_config.status_topic.clone()).filter(|t| !t.is_empty()),
+            stop: Arc::new(AtomicBool::new(false)),
+            ha: light_ha,
+            lights,
+            current_sensors,
+            reconnect_sensor: device.reconnect_sensor(),
+            strips: segmented,
+            state_file: StateFile::new(&config.state.path),
+        };
+        light_strip.power_on(&config.state);
+        light_strip
+    }
+
+    fn power_on(&mut self, config: &config::StateConfig) {
+        let mut saved = self.state_file.load();
+        for index in 0..self.ha.len() {
+            let state = saved.remove(self.ha[index].unique_id());
+            let light = self.light_mut(index);
+            if let Some(state) = state {
+                light.restore(state);
+            }
+            match config.power_on {
+                PowerOn::Restore => {}
+                PowerOn::Off => light.turn_off(),
+                PowerOn::On => {
+                    let [r, g, b] = config.default_colour;
+                    light.set_rgb(r, g, b);
+                    light.set_brightness(config.default_brightness as f32 / 255.0);
+                }
+            }
         }
     }
 
-    pub async fn run(&mut self) {
-        let (client, mut connection) = AsyncClient::new(self.mqtt_options.clone(), 1);
+    fn save_state(&mut self) {
+        let states = (0..self.ha.len())
+            .map(|index| {
+                (
+                    self.ha[index].unique_id().to_string(),
+                    self.light(index).state(),
+                )
+            })
+            .collect();
+        if let Err(e) = self.state_file.save(&states) {
+            log::error!("Error saving state: {}", e);
+        }
+    }
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+    pub async fn run(&mut self) {
+        let (client, connection) = AsyncClient::new(self.mqtt_options.clone(), 10);
 
-        log::info!("Subscribing to {}", self.ha.command_topic);
-        client
-            .subscribe(&self.ha.command_topic, QoS::AtMostOnce)
-            .await
-            .expect("Error subscribing");
+        // Unbounded so the event loop never waits on the render loop, which may be
+        // waiting on the event loop to take its publishes.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
 
-        log::info!("Starting Online thread");
-        let online_message = self.ha.set_online();
-        let online_client = client.clone();
+        let stop = self.stop.clone();
         task::spawn(async move {
-            loop {
-                let (topic, payload) = online_message.clone();
-                LightStrip::publish(&online_client, &topic, &payload, false).await;
-                sleep(Duration::from_secs(60)).await;
+            let mut terminate = signal(SignalKind::terminate()).expect("Error handling SIGTERM");
+            tokio::select! {
+                _ = terminate.recv() => log::info!("Received SIGTERM"),
+                _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
             }
+            stop.store(true, Ordering::Relaxed);
         });
 
+        let command_topics = self
+            .ha
+            .iter()
+            .map(|ha| ha.command_topic.clone())
+            .collect::<Vec<String>>();
+
         log::info!("Starting State thread");
-        task::spawn(async move {
-            while let Ok(notification) = connection.poll().await {
-                let message = match notification {
-                    rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) => p,
-                    _ => continue,
-                };
-                let message = String::from_utf8(message.payload.to_vec()).unwrap();
-
-                match StripMode::from_str(&message) {
-                    Ok(state) => {
-                        if let Err(e) = tx.send(state).await {
-                            log::error!("Error sending state: {:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("Error parsing message: {:?}", e);
+        let poll = task::spawn(LightStrip::poll(
+            connection,
+            client.clone(),
+            command_topics,
+            self.status_topic.clone(),
+            tx,
+            self.stop.clone(),
+        ));
+
+        let mut connected = false;
+        let mut connections = 0;
+        let mut last_current = Instant::now();
+        while !self.stop.load(Ordering::Relaxed) {
+            match rx.try_recv() {
+                Ok(MqttEvent::Connected) => {
+                    connected = true;
+                    connections += 1;
+                    self.announce(&client, connections - 1).await;
+                }
+                Ok(MqttEvent::HomeAssistantOnline) => {
+                    log::info!("Home Assistant is back, announcing again");
+                    self.announce(&client, connections.saturating_sub(1)).await;
+                }
+                Ok(MqttEvent::Disconnected) => {
+                    connected = false;
+                }
+                Ok(MqttEvent::Command(index, command)) => {
+                    // The state goes out either way, so HA drops a rejected change.
+                    match self.handle_state_change(index, &command) {
+                        Ok(()) => self.state_file.changed(),
+                        Err(e) => log::error!("Error applying command: {}", e),
                     }
+                    let payload = self.light(index).state_message();
+                    let topic = self.ha[index].state_topic.clone();
+                    LightStrip::publish(&client, &topic, &payload, true).await;
                 }
+                Err(_) => {}
             }
-        });
 
-        log::info!("Sending discovery message");
-        let (disco_topic, disco_payload) = self.ha.discovery_message();
-        LightStrip::publish(&client, &disco_topic, &disco_payload, true).await;
+            for strip in &mut self.strips {
+                strip.update();
+            }
 
-        while !self.stop.load(Ordering::Relaxed) {
-            if let Ok(state) = rx.try_recv() {
-                self.handle_state_change(&state);
-                let payload = self.strip.state_message();
-                let topic = self.ha.state_topic.clone();
-                LightStrip::publish(&client, &topic, &payload, true).await;
+            if self.state_file.due() {
+                self.save_state();
+            }
+
+            // Anything missed while offline is sent again by `announce`.
+            if connected && last_current.elapsed() >= CURRENT_INTERVAL {
+                last_current = Instant::now();
+                for (sensor, strip) in self.current_sensors.iter().zip(&self.strips) {
+                    let (topic, payload) = sensor.state_message(strip.current_draw());
+                    LightStrip::publish(&client, &topic, &payload, false).await;
+                }
             }
 
-            self.strip.update();
             sleep(UPDATE_INTERVAL).await;
         }
+
+        drop(rx);
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.shutdown(&client, poll, connected))
+            .await
+            .is_err()
+        {
+            log::warn!("Gave up shutting down cleanly");
+        }
+    }
+
+    /// Drives the MQTT connection, reconnecting with a growing delay whenever it
+    /// drops, until the client disconnects.
+    async fn poll(
+        mut connection: EventLoop,
+        client: AsyncClient,
+        command_topics: Vec<String>,
+        status_topic: Option<String>,
+        tx: UnboundedSender<MqttEvent>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let message = match connection.poll().await {
+                Ok(Event::Incoming(Packet::Publish(p))) => p,
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("Connected to broker");
+                    backoff = MIN_BACKOFF;
+                    // A clean session forgets subscriptions. The event loop can't
+                    // wait on its own request queue, so don't block, and send them
+                    // as one request so any number of lights fits in the queue.
+                    let filters = command_topics
+                        .iter()
+                        .chain(&status_topic)
+                        .map(|topic| {
+                            log::info!("Subscribing to {}", topic);
+                            SubscribeFilter::new(topic.clone(), QoS::AtMostOnce)
+                        })
+                        .collect::<Vec<SubscribeFilter>>();
+                    if let Err(e) = client.try_subscribe_many(filters) {
+                        log::error!("Error subscribing: {:?}", e);
+                    }
+                    let _ = tx.send(MqttEvent::Connected);
+                    continue;
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => continue,
+                Err(e) => {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    log::warn!("Connection lost: {}, retrying in {:?}", e, backoff);
+                    let _ = tx.send(MqttEvent::Disconnected);
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            if status_topic.as_ref() == Some(&message.topic) {
+                // The birth message is retained, so every subscribe replays it.
+                // Connecting already announced, only a live one means HA restarted.
+                if !message.retain && message.payload.as_ref() == b"online" {
+                    let _ = tx.send(MqttEvent::HomeAssistantOnline);
+                }
+                continue;
+            }
+            let Some(index) = command_topics.iter().position(|t| *t == message.topic) else {
+                continue;
+            };
+            let message = String::from_utf8_lossy(&message.payload);
+
+            match Command::from_str(&message) {
+                Ok(command) => {
+                    let _ = tx.send(MqttEvent::Command(index, command));
+                }
+                Err(e) => {
+                    log::error!("Error parsing message: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Everything HA needs to show the lights, sent on every connect.
+    async fn announce(&self, client: &AsyncClient, reconnects: u32) {
+        log::info!("Sending discovery message");
+        for ha in &self.ha {
+            let (disco_topic, disco_payload) = ha.discovery_message();
+            LightStrip::publish(client, &disco_topic, &disco_payload, true).await;
+        }
+        for sensor in self.current_sensors.iter().chain([&self.reconnect_sensor]) {
+            let (disco_topic, disco_payload) = sensor.discovery_message();
+            LightStrip::publish(client, &disco_topic, &disco_payload, true).await;
+        }
+
+        let (topic, payload) = self.ha[0].set_online();
+        LightStrip::publish(client, &topic, &payload, true).await;
+
+        for (index, ha) in self.ha.iter().enumerate() {
+            let payload = self.light(index).state_message();
+            LightStrip::publish(client, &ha.state_topic, &payload, true).await;
+        }
+
+        let (topic, payload) = self.reconnect_sensor.state_message(reconnects as f32);
+        LightStrip::publish(client, &topic, &payload, true).await;
+    }
+
+    /// Blanks the LEDs, tells HA the light is gone and waits for that to reach the broker.
+    async fn shutdown(&mut self, client: &AsyncClient, poll: JoinHandle<()>, connected: bool) {
+        log::info!("Shutting down");
+        for strip in &mut self.strips {
+            strip.blank();
+        }
+        if self.state_file.pending() {
+            self.save_state();
+        }
+        if !connected {
+            poll.abort();
+            return;
+        }
+
+        for (index, ha) in self.ha.iter().enumerate() {
+            let payload = self.light(index).state_message();
+            LightStrip::publish(client, &ha.state_topic, &payload, true).await;
+        }
+        let (topic, payload) = self.ha[0].set_offline();
+        LightStrip::publish(client, &topic, &payload, true).await;
+
+        if let Err(e) = client.disconnect().await {
+            log::error!("Error disconnecting: {:?}", e);
+        }
+        let _ = poll.await;
     }
 
     async fn publish(client: &AsyncClient, topic: &String, message: &String, retain: bool) {
@@ -123,25 +379,56 @@ impl LightStrip {
         }
     }
 
-    fn handle_state_change(&mut self, state: &StripMode) {
-        log::info!("State change: {}", state);
+    fn light(&self, index: usize) -> &MyStrip {
+        let (strip, segment) = self.lights[index];
+        self.strips[strip].light(segment)
+    }
 
-        match state {
-            StripMode::Brightness(brightness) => {
-                self.strip.set_brightness(*brightness as f32 / 255.0);
-            }
-            StripMode::On => {
-                self.strip.turn_on();
-            }
-            StripMode::Off => {
-                self.strip.turn_off();
-            }
-            StripMode::Colour(r, g, b) => {
-                self.strip.set_rgb(*r, *g, *b);
-            }
-            StripMode::Effect(e) => {
-                self.strip.set_effect(e);
+    fn light_mut(&mut self, index: usize) -> &mut MyStrip {
+        let (strip, segment) = self.lights[index];
+        self.strips[strip].light_mut(segment)
+    }
+
+    fn handle_state_change(&mut self, index: usize, command: &Command) -> Result<(), String> {
+        log::info!("State change: {}: {}", self.ha[index].name(), command);
+
+        // Checked before anything changes so a bad command leaves the light as it was.
+        if let Some(effect) = &command.effect {
+            if !self.light(index).has_effect(effect) {
+                return Err(format!("Unknown effect {}", effect));
             }
         }
+
+        let flash_time = command.flash.map(|flash| self.ha[index].flash_time(flash));
+        let light = self.light_mut(index);
+        light.end_flash();
+        if !command.on {
+            light.begin_transition(command.transition);
+            light.turn_off();
+            return Ok(());
+        }
+
+        match flash_time {
+            Some(duration) => light.begin_flash(duration),
+            None => light.begin_transition(command.transition),
+        }
+        light.turn_on();
+        match &command.colour {
+            Some(Colour::Rgb(r, g, b)) => light.set_rgb(*r, *g, *b),
+            Some(Colour::Rgbw(r, g, b, w)) => light.set_rgbw(*r, *g, *b, *w),
+            Some(Colour::Hs(h, s)) => light.set_hs(*h, *s / 100.0),
+            Some(Colour::Xy(x, y)) => light.set_xy(*x, *y),
+            None => {}
+        }
+        if let Some(mireds) = command.color_temp {
+            light.set_temperature(mireds);
+        }
+        if let Some(effect) = &command.effect {
+            light.set_effect(effect)?;
+        }
+        if let Some(brightness) = command.brightness {
+            light.set_brightness(brightness as f32 / 255.0);
+        }
+        Ok(())
     }
 }
@@ -1,14 +1,14 @@
 use log::LevelFilter;
 // use simple_logger::SimpleLogger;
+use crate::ws2812::{Output, RecordingOutput, SpiOutput, Strip};
+use ::light_strip::ws2812;
 use std::env;
 use systemd_journal_logger::JournalLog;
-mod ws2812;
-use crate::ws2812::Strip;
-use rppal::spi::{Bus, SlaveSelect};
 
 mod config;
 mod homeassistant;
 mod light_strip;
+mod state;
 
 #[tokio::main]
 async fn main() {
@@ -34,9 +34,48 @@ async fn main() {
     };
     log::info!("Config Loaded: {}", conf);
 
-    let mut led = Strip::new(Bus::Spi0, SlaveSelect::Ss0, 55, 2).expect("Error creating strip");
-    let _ = led.clear(0);
-    let _ = led.clear(1);
+    if let Err(e) = conf.validate() {
+        panic!("Invalid strip config: {}", e);
+    }
 
-    light_strip::LightStrip::new(&conf, None, led).run().await;
+    let strips = conf.strips().iter().map(build_strip).collect();
+
+    light_strip::LightStrip::new(&conf, None, strips)
+        .run()
+        .await;
+}
+
+fn build_strip(strip_config: &config::StripConfig) -> Strip {
+    let max_transfer = strip_config.max_transfer();
+    let encoding = strip_config.encoding(max_transfer);
+    let output: Box<dyn Output> = match strip_config.output {
+        config::OutputType::Spi => Box::new(
+            SpiOutput::new(
+                strip_config.spi_bus().unwrap(),
+                strip_config.spi_slave_select().unwrap(),
+                strip_config.spi_clock_hz(encoding),
+                max_transfer,
+            )
+            .unwrap_or_else(|e| panic!("Error creating SPI output: {}", e)),
+        ),
+        config::OutputType::Recording => Box::new(RecordingOutput::new()),
+    };
+
+    let mut led = Strip::new(
+        output,
+        strip_config.count,
+        strip_config.pages,
+        strip_config.channel_order(),
+        strip_config.chip,
+        encoding,
+        strip_config.split_frames,
+    )
+    .unwrap_or_else(|e| panic!("Error creating strip: {}", e));
+    led.set_calibration(strip_config.calibration.calibration());
+    led.set_dithering(strip_config.dithering);
+    led.set_power_budget(strip_config.power.budget());
+    for page in 0..strip_config.pages {
+        let _ = led.clear(page);
+    }
+    led
 }
@@ -0,0 +1,66 @@
+use crate::ws2812::LightState;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::time::{Duration, Instant};
+
+/// Waits for changes to settle so dragging a slider in HA is one write, not hundreds.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Every light's state, keyed by its unique id, kept in a JSON file.
+pub struct StateFile {
+    path: String,
+    changed: Option<Instant>,
+}
+
+impl StateFile {
+    pub fn new(path: &str) -> StateFile {
+        StateFile {
+            path: path.to_string(),
+            changed: None,
+        }
+    }
+
+    /// A missing or broken file starts every light fresh.
+    pub fn load(&self) -> HashMap<String, LightState> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::info!("No saved state in {}: {}", self.path, e);
+                return HashMap::new();
+            }
+        };
+        serde_json::from_reader(file).unwrap_or_else(|e| {
+            log::error!("Failed to parse state file {}: {}", self.path, e);
+            HashMap::new()
+        })
+    }
+
+    pub fn changed(&mut self) {
+        self.changed = Some(Instant::now());
+    }
+
+    pub fn pending(&self) -> bool {
+        self.changed.is_some()
+    }
+
+    pub fn due(&self) -> bool {
+        self.changed
+            .is_some_and(|changed| changed.elapsed() >= SAVE_DELAY)
+    }
+
+    /// Writes next to the file and renames over it, so a crash never leaves half a file.
+    pub fn save(&mut self, states: &HashMap<String, LightState>) -> Result<(), String> {
+        self.changed = None;
+
+        let temp = format!("{}.tmp", self.path);
+        let file =
+            File::create(&temp).map_err(|e| format!("Failed to create state file: {}", e))?;
+        serde_json::to_writer_pretty(&file, states)
+            .map_err(|e| format!("Failed to write state file: {}", e))?;
+        file.sync_all()
+            .map_err(|e| format!("Failed to write state file: {}", e))?;
+        fs::rename(&temp, &self.path)
+            .map_err(|e| format!("Failed to replace state file: {}", e))?;
+        Ok(())
+    }
+}
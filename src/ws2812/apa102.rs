@@ -0,0 +1,28 @@
+use crate::ws2812::{ChannelOrder, Rgb};
+
+const HEADER: u8 = 0xE0;
+const MAX_GLOBAL: f32 = 31.0;
+
+pub const START_FRAME: [u8; 4] = [0u8; 4];
+
+/// The data is delayed by half a clock per LED, so the end frame needs at least
+/// `count / 2` more clock edges. Zeros also double as the SK9822 reset frame.
+pub fn end_frame_len(count: usize) -> usize {
+    4 + count.div_ceil(16)
+}
+
+/// Splits a brightness level into the 5-bit global field and the scale left for RGB,
+/// so low brightness dims in hardware and keeps the full colour resolution.
+pub fn global_brightness(level: f32) -> (u8, f32) {
+    let level = level.clamp(0.0, 1.0) * MAX_GLOBAL;
+    let global = level.ceil() as u8;
+    if global == 0 {
+        return (0, 0.0);
+    }
+    (global, level / global as f32)
+}
+
+pub fn led_frame(rgb: &Rgb, order: ChannelOrder, global: u8) -> [u8; 4] {
+    let [a, b, c] = order.arrange(rgb.red, rgb.green, rgb.blue);
+    [HEADER | global, a, b, c]
+}
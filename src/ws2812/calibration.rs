@@ -0,0 +1,120 @@
+use crate::ws2812::Rgbw;
+
+/// Gamma curve and white balance, baked into one lookup table per channel.
+/// Entries stay in f32 so nothing is lost before the driver quantises.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibration {
+    gamma: f32,
+    tables: [[f32; 256]; 4],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration::new(1.0, [1.0; 4])
+    }
+}
+
+impl Calibration {
+    /// `scales` are the red, green, blue and white factors applied after the curve.
+    pub fn new(gamma: f32, scales: [f32; 4]) -> Calibration {
+        let tables = scales.map(|scale| Self::table(gamma, scale));
+        Calibration { gamma, tables }
+    }
+
+    fn table(gamma: f32, scale: f32) -> [f32; 256] {
+        let mut table = [0.0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let linear = (i as f32 / 255.0).powf(gamma) * scale;
+            *entry = linear.clamp(0.0, 1.0) * 255.0;
+        }
+        table
+    }
+
+    /// Red, green, blue and white on a 0.0..=255.0 scale.
+    pub fn apply(&self, pixel: &Rgbw) -> [f32; 4] {
+        [
+            self.tables[0][pixel.red as usize],
+            self.tables[1][pixel.green as usize],
+            self.tables[2][pixel.blue as usize],
+            self.tables[3][pixel.white as usize],
+        ]
+    }
+
+    /// The curve applied to a brightness level, so dimming follows the same response.
+    pub fn level(&self, brightness: f32) -> f32 {
+        brightness.clamp(0.0, 1.0).powf(self.gamma)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn gamma_one_is_the_identity() {
+        let calibration = Calibration::new(1.0, [1.0; 4]);
+        for i in 0..=255u8 {
+            let channels = calibration.apply(&Rgbw::new(i, i, i, i));
+            channels.iter().for_each(|c| assert_close(*c, i as f32));
+        }
+    }
+
+    #[test]
+    fn gamma_follows_the_curve() {
+        let calibration = Calibration::new(2.2, [1.0; 4]);
+        for (input, output) in [
+            (0, 0.0),
+            (64, 12.183),
+            (128, 55.978),
+            (192, 136.589),
+            (255, 255.0),
+        ] {
+            assert_close(calibration.apply(&Rgbw::new(input, 0, 0, 0))[0], output);
+        }
+    }
+
+    #[test]
+    fn scales_after_the_curve() {
+        let calibration = Calibration::new(2.2, [0.5, 1.0, 0.25, 0.0]);
+        let [red, green, blue, white] = calibration.apply(&Rgbw::new(128, 128, 128, 128));
+        assert_close(red, 55.978 * 0.5);
+        assert_close(green, 55.978);
+        assert_close(blue, 55.978 * 0.25);
+        assert_close(white, 0.0);
+    }
+
+    #[test]
+    fn stays_in_range() {
+        for gamma in [0.1, 0.5, 1.0, 2.2, 4.0] {
+            let calibration = Calibration::new(gamma, [1.0; 4]);
+            for i in 0..=255u8 {
+                let channels = calibration.apply(&Rgbw::new(i, i, i, i));
+                assert!(channels.iter().all(|c| (0.0..=255.0).contains(c)));
+            }
+        }
+    }
+
+    #[test]
+    fn level_follows_the_curve() {
+        let calibration = Calibration::new(2.2, [1.0; 4]);
+        assert_close(calibration.level(0.0), 0.0);
+        assert_close(calibration.level(0.5), 0.2176);
+        assert_close(calibration.level(1.0), 1.0);
+        assert_close(calibration.level(2.0), 1.0);
+        for i in [0, 64, 128, 192, 255u8] {
+            assert_close(
+                calibration.level(i as f32 / 255.0) * 255.0,
+                calibration.apply(&Rgbw::new(i, 0, 0, 0))[0],
+            );
+        }
+    }
+}
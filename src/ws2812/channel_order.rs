@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// The order the chip expects colour bytes on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChannelOrder {
+    Rgb,
+    Rbg,
+    #[default]
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl ChannelOrder {
+    pub fn arrange(&self, red: u8, green: u8, blue: u8) -> [u8; 3] {
+        match self {
+            ChannelOrder::Rgb => [red, green, blue],
+            ChannelOrder::Rbg => [red, blue, green],
+            ChannelOrder::Grb => [green, red, blue],
+            ChannelOrder::Gbr => [green, blue, red],
+            ChannelOrder::Brg => [blue, red, green],
+            ChannelOrder::Bgr => [blue, green, red],
+        }
+    }
+}
+
+impl Display for ChannelOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChannelOrder::Rgb => "RGB",
+            ChannelOrder::Rbg => "RBG",
+            ChannelOrder::Grb => "GRB",
+            ChannelOrder::Gbr => "GBR",
+            ChannelOrder::Brg => "BRG",
+            ChannelOrder::Bgr => "BGR",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws2812::{Chip, Encoder, Encoding, RecordingOutput, Rgb, Rgbw, Strip};
+
+    const ORDERS: [(ChannelOrder, [u8; 3]); 6] = [
+        (ChannelOrder::Rgb, [1, 2, 3]),
+        (ChannelOrder::Rbg, [1, 3, 2]),
+        (ChannelOrder::Grb, [2, 1, 3]),
+        (ChannelOrder::Gbr, [2, 3, 1]),
+        (ChannelOrder::Brg, [3, 1, 2]),
+        (ChannelOrder::Bgr, [3, 2, 1]),
+    ];
+
+    #[test]
+    fn arranges_rgb_and_rgbw() {
+        for (order, [a, b, c]) in ORDERS {
+            assert_eq!(Rgb::new(1, 2, 3).to_array(order), [a, b, c], "{}", order);
+            assert_eq!(
+                Rgbw::new(1, 2, 3, 4).to_array(order),
+                [a, b, c, 4],
+                "{}",
+                order
+            );
+        }
+    }
+
+    fn encoded(chip: Chip, order: ChannelOrder) -> Vec<u8> {
+        let output = RecordingOutput::new();
+        let mut strip = Strip::new(
+            Box::new(output.clone()),
+            1,
+            1,
+            order,
+            chip,
+            Encoding::Spi24,
+            false,
+        )
+        .unwrap();
+        strip.set_led(0, 0, &Rgbw::new(1, 2, 3, 4)).unwrap();
+        strip.refresh(0).unwrap();
+
+        let mut frame = output.frames().remove(0);
+        frame.truncate(chip.channels() * Encoding::Spi24.width());
+        frame
+    }
+
+    #[test]
+    fn strip_sends_channels_in_order() {
+        let encoder = Encoder::new(Encoding::Spi24);
+        for (order, [a, b, c]) in ORDERS {
+            // Without a white LED the white channel is folded into the others.
+            let mut expected = Vec::new();
+            encoder.extend(&mut expected, &[a + 4, b + 4, c + 4]);
+            assert_eq!(encoded(Chip::Ws2812, order), expected, "{}", order);
+
+            let mut expected = Vec::new();
+            encoder.extend(&mut expected, &[a, b, c, 4]);
+            assert_eq!(encoded(Chip::Sk6812Rgbw, order), expected, "{}", order);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::ws2812::ChannelOrder;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chip {
+    #[default]
+    Ws2812,
+    Sk6812Rgbw,
+    Apa102,
+    Sk9822,
+}
+
+impl Chip {
+    pub fn has_white(&self) -> bool {
+        matches!(self, Chip::Sk6812Rgbw)
+    }
+
+    /// Clocked chips take data and clock lines rather than a timed waveform.
+    pub fn is_clocked(&self) -> bool {
+        matches!(self, Chip::Apa102 | Chip::Sk9822)
+    }
+
+    /// Bytes of pixel data per LED, before any encoding.
+    pub fn channels(&self) -> usize {
+        match self {
+            Chip::Ws2812 => 3,
+            Chip::Sk6812Rgbw | Chip::Apa102 | Chip::Sk9822 => 4,
+        }
+    }
+
+    pub fn default_order(&self) -> ChannelOrder {
+        if self.is_clocked() {
+            ChannelOrder::Bgr
+        } else {
+            ChannelOrder::Grb
+        }
+    }
+}
+
+impl Display for Chip {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip::Ws2812 => write!(f, "WS2812"),
+            Chip::Sk6812Rgbw => write!(f, "SK6812 RGBW"),
+            Chip::Apa102 => write!(f, "APA102"),
+            Chip::Sk9822 => write!(f, "SK9822"),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+/// Quantises high precision frames to 8-bit, carrying each LED's rounding error
+/// into the next frame so averages over time hit the in-between levels.
+#[derive(Clone, Debug, Default)]
+pub struct Dither {
+    enabled: bool,
+    error: Vec<[f32; 4]>,
+}
+
+impl Dither {
+    pub fn new(enabled: bool) -> Dither {
+        Dither {
+            enabled,
+            error: Vec::new(),
+        }
+    }
+
+    pub fn quantise(&mut self, led: usize, channels: [f32; 4]) -> [u8; 4] {
+        if !self.enabled {
+            return channels.map(|c| c.round().clamp(0.0, 255.0) as u8);
+        }
+
+        if self.error.len() <= led {
+            self.error.resize(led + 1, [0.0; 4]);
+        }
+
+        let error = &mut self.error[led];
+        let mut out = [0u8; 4];
+        for i in 0..4 {
+            let target = channels[i] + error[i];
+            let value = target.round().clamp(0.0, 255.0);
+            // Don't let a channel that is meant to be off keep glowing from old error.
+            error[i] = if channels[i] > 0.0 {
+                target - value
+            } else {
+                0.0
+            };
+            out[i] = value as u8;
+        }
+        out
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// How long the line is held low after a frame so the LEDs latch it.
+const LATCH_US: usize = 50;
+
+/// The spidev default, used to pick an encoding when the real limit isn't known.
+pub const DEFAULT_MAX_TRANSFER: usize = 4096;
+
+/// How many SPI bits make up one LED data bit, each with its own SPI clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// The widest encoding that still fits in one transfer.
+    #[default]
+    Auto,
+    /// 24 SPI bits per data bit at 32 MHz.
+    Spi24,
+    /// 4 SPI bits per data bit at 3.2 MHz.
+    Spi4,
+    /// 3 SPI bits per data bit at 2.4 MHz.
+    Spi3,
+}
+
+impl Encoding {
+    /// Picks `Spi24` if a frame of `count` LEDs with `channels` bytes each fits in
+    /// `max_transfer` bytes, then the compact encodings, narrowest last.
+    pub fn resolve(&self, count: usize, channels: usize, max_transfer: usize) -> Encoding {
+        if *self != Encoding::Auto {
+            return *self;
+        }
+        [Encoding::Spi24, Encoding::Spi4]
+            .into_iter()
+            .find(|e| e.frame_len(count, channels) <= max_transfer)
+            .unwrap_or(Encoding::Spi3)
+    }
+
+    pub fn clock_hz(&self) -> u32 {
+        match self {
+            Encoding::Auto | Encoding::Spi24 => 32_000_000,
+            Encoding::Spi4 => 3_200_000,
+            Encoding::Spi3 => 2_400_000,
+        }
+    }
+
+    /// The zero and one waveforms, MSB first, and how many bits each is.
+    fn waveform(&self) -> (u32, u32, usize) {
+        match self {
+            Encoding::Auto | Encoding::Spi24 => (0xFE_00_00, 0xFF_FE_00, 24),
+            Encoding::Spi4 => (0b1000, 0b1110, 4),
+            Encoding::Spi3 => (0b100, 0b110, 3),
+        }
+    }
+
+    /// SPI bytes needed for one byte of LED data.
+    pub fn width(&self) -> usize {
+        self.waveform().2
+    }
+
+    pub fn latch_len(&self) -> usize {
+        (LATCH_US * self.clock_hz() as usize).div_ceil(8_000_000)
+    }
+
+    /// Bytes of LED data in a frame. The latch goes out as its own transfer, a
+    /// gap in a line that is held low anyway does no harm, so it isn't counted.
+    pub fn frame_len(&self, count: usize, channels: usize) -> usize {
+        count * channels * self.width()
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Auto => write!(f, "auto"),
+            Encoding::Spi24 => write!(f, "24 bit"),
+            Encoding::Spi4 => write!(f, "4 bit"),
+            Encoding::Spi3 => write!(f, "3 bit"),
+        }
+    }
+}
+
+/// Expands every possible byte into its SPI waveform once, up front, so encoding
+/// a frame is just table lookups into a reused buffer.
+pub struct Encoder {
+    encoding: Encoding,
+    table: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Encoder {
+        let (zero, one, bits) = encoding.waveform();
+        let width = encoding.width();
+        let mut table = Vec::with_capacity(256 * width);
+
+        for byte in 0..=255u8 {
+            let mut acc = 0u8;
+            let mut filled = 0;
+            for i in 0..8 {
+                let pattern = if (byte >> (7 - i)) & 0x01 == 1 {
+                    one
+                } else {
+                    zero
+                };
+                for b in (0..bits).rev() {
+                    acc = (acc << 1) | ((pattern >> b) & 0x01) as u8;
+                    filled += 1;
+                    if filled == 8 {
+                        table.push(acc);
+                        acc = 0;
+                        filled = 0;
+                    }
+                }
+            }
+        }
+
+        Encoder { encoding, table }
+    }
+
+    pub fn encode(&self, byte: u8) -> &[u8] {
+        let width = self.encoding.width();
+        let start = byte as usize * width;
+        &self.table[start..start + width]
+    }
+
+    pub fn extend(&self, buffer: &mut Vec<u8>, bytes: &[u8]) {
+        for byte in bytes {
+            buffer.extend_from_slice(self.encode(*byte));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi24_matches_the_original_waveform() {
+        let encoder = Encoder::new(Encoding::Spi24);
+        for byte in 0..=255u8 {
+            let expected = (0..8)
+                .flat_map(|i| {
+                    if (byte >> (7 - i)) & 0x01 == 1 {
+                        [0xFF, 0xFE, 0x00]
+                    } else {
+                        [0xFE, 0x00, 0x00]
+                    }
+                })
+                .collect::<Vec<u8>>();
+            assert_eq!(encoder.encode(byte), expected, "byte {:#04x}", byte);
+        }
+    }
+
+    #[test]
+    fn encodes_each_waveform() {
+        let spi24 = Encoder::new(Encoding::Spi24);
+        assert_eq!(spi24.encode(0x00), [0xFE, 0x00, 0x00].repeat(8));
+        assert_eq!(spi24.encode(0xFF), [0xFF, 0xFE, 0x00].repeat(8));
+        assert_eq!(
+            spi24.encode(0xA5),
+            [
+                0xFF, 0xFE, 0x00, 0xFE, 0x00, 0x00, 0xFF, 0xFE, 0x00, 0xFE, 0x00, 0x00, 0xFE, 0x00,
+                0x00, 0xFF, 0xFE, 0x00, 0xFE, 0x00, 0x00, 0xFF, 0xFE, 0x00
+            ]
+        );
+
+        let spi4 = Encoder::new(Encoding::Spi4);
+        assert_eq!(spi4.encode(0x00), [0x88, 0x88, 0x88, 0x88]);
+        assert_eq!(spi4.encode(0xFF), [0xEE, 0xEE, 0xEE, 0xEE]);
+        assert_eq!(spi4.encode(0xA5), [0xE8, 0xE8, 0x8E, 0x8E]);
+
+        let spi3 = Encoder::new(Encoding::Spi3);
+        assert_eq!(spi3.encode(0x00), [0x92, 0x49, 0x24]);
+        assert_eq!(spi3.encode(0xFF), [0xDB, 0x6D, 0xB6]);
+        assert_eq!(spi3.encode(0xA5), [0xD3, 0x49, 0xA6]);
+    }
+
+    #[test]
+    fn latch_holds_the_line_low_for_50us() {
+        assert_eq!(Encoding::Spi24.latch_len(), 200);
+        assert_eq!(Encoding::Spi4.latch_len(), 20);
+        assert_eq!(Encoding::Spi3.latch_len(), 15);
+    }
+
+    #[test]
+    fn resolves_the_widest_encoding_that_fits() {
+        let resolve = |count| Encoding::Auto.resolve(count, 3, DEFAULT_MAX_TRANSFER);
+        // The latch doesn't count, so the default 55 LED strip stays on 24 bit.
+        assert_eq!(resolve(55), Encoding::Spi24);
+        assert_eq!(resolve(56), Encoding::Spi24);
+        assert_eq!(resolve(57), Encoding::Spi4);
+        assert_eq!(resolve(341), Encoding::Spi4);
+        assert_eq!(resolve(342), Encoding::Spi3);
+        assert_eq!(
+            Encoding::Spi24.resolve(342, 3, DEFAULT_MAX_TRANSFER),
+            Encoding::Spi24
+        );
+    }
+}
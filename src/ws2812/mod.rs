@@ -1,11 +1,46 @@
 mod strip;
-pub use strip::Strip;
+pub use strip::{Strip, MAX_LEDS};
+
+mod apa102;
+
+mod calibration;
+pub use calibration::Calibration;
+
+mod chip;
+pub use chip::Chip;
+
+mod channel_order;
+pub use channel_order::ChannelOrder;
 
 mod rgb;
 pub use rgb::Rgb;
 
+mod rgbw;
+pub use rgbw::Rgbw;
+
+mod white;
+pub use white::{WhiteExtraction, KELVIN_RANGE};
+
+mod dither;
+pub use dither::Dither;
+
+mod power;
+pub use power::PowerBudget;
+
+mod encoder;
+pub use encoder::{Encoder, Encoding, DEFAULT_MAX_TRANSFER};
+
 mod ws2812_error;
 pub use ws2812_error::Ws2812Error;
 
+mod transition;
+pub use transition::Transition;
+
 mod my_strip;
-pub use my_strip::{MyStrip, RunMode};
+pub use my_strip::{LightState, MyStrip, MAX_MIREDS, MIN_MIREDS};
+
+mod segment;
+pub use segment::{Segment, SegmentedStrip};
+
+mod output;
+pub use output::{spidev_bufsiz, Output, RecordingOutput, SpiOutput};
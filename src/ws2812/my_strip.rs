@@ -1,16 +1,50 @@
-use crate::ws2812::{Rgb, Strip};
-use palette::{Darken, FromColor, Hsv};
+use crate::ws2812::{Rgb, Rgbw, Transition, WhiteExtraction};
+use palette::{FromColor, Hsv, LinSrgb, Yxy};
+use serde::{Deserialize, Serialize};
 use smart_led_effects::strip::EffectIterator;
 use smart_led_effects::{strip, Srgb};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, PartialEq)]
+/// The colour temperatures Home Assistant can ask for, 6500 K to 2000 K.
+pub const MIN_MIREDS: u16 = 153;
+pub const MAX_MIREDS: u16 = 500;
+
+/// One on and off blink of a flash.
+const FLASH_PERIOD: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RunMode {
-    Static(f32, f32),
+    Rgb(u8, u8, u8),
+    /// Hue in degrees, saturation from 0 to 1.
+    Hs(f32, f32),
+    /// A CIE xy chromaticity.
+    Xy(f32, f32),
+    Rgbw(u8, u8, u8, u8),
+    /// White at a colour temperature, in mireds.
+    Temperature(u16),
     Dynamic(String),
     Off,
 }
 
+/// What a light needs to come back the way it was left.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LightState {
+    pub mode: RunMode,
+    pub brightness: f32,
+    pub previous_mode: RunMode,
+    pub previous_brightness: f32,
+}
+
+/// Blinks whatever the light is set to, then puts back the mode and brightness it replaced.
+struct Flash {
+    mode: RunMode,
+    brightness: f32,
+    start: Instant,
+    duration: Duration,
+}
+
 pub struct MyStrip {
     pub mode: RunMode,
     pub brightness: f32,
@@ -18,12 +52,21 @@ pub struct MyStrip {
     effects_map: HashMap<String, Box<dyn EffectIterator>>,
     previous_mode: RunMode,
     previous_brightness: f32,
-
-    strip: Strip,
+    white: WhiteExtraction,
+    count: usize,
+    default_transition: Duration,
+    transition: Option<Transition>,
+    flash: Option<Flash>,
+    /// The last frame the mode rendered, the target of a transition.
+    frame: Vec<Rgbw>,
+    /// What is on the LEDs, where the next transition starts.
+    displayed: Vec<Rgbw>,
+    level: f32,
 }
 
 impl MyStrip {
-    pub fn new(count: usize, strip: Strip) -> Self {
+    /// One light of `count` LEDs. Only renders pixels, `SegmentedStrip` puts them on a `Strip`.
+    pub fn new(count: usize, white: WhiteExtraction, default_transition: Duration) -> Self {
         let mut effects_map: HashMap<String, Box<dyn EffectIterator>> = HashMap::new();
         let effects = strip::get_all_default_effects(count);
         for effect in effects {
@@ -36,10 +79,85 @@ impl MyStrip {
             effects_map,
             previous_mode: RunMode::Off,
             previous_brightness: 1.0,
-            strip,
+            white,
+            count,
+            default_transition,
+            transition: None,
+            flash: None,
+            frame: vec![Rgbw::new(0, 0, 0, 0); count],
+            displayed: vec![Rgbw::new(0, 0, 0, 0); count],
+            level: 1.0,
+        }
+    }
+
+    /// Fades from what is shown now to whatever the light is set to before the next
+    /// frame, over `duration` or the configured default.
+    pub fn begin_transition(&mut self, duration: Option<Duration>) {
+        let duration = duration.unwrap_or(self.default_transition);
+        if duration.is_zero() {
+            self.transition = None;
+            return;
+        }
+
+        let from_effect = match (&self.transition, &self.mode) {
+            (None, RunMode::Dynamic(e)) => Some(e.clone()),
+            _ => None,
+        };
+        self.transition = Some(Transition::new(
+            self.displayed.clone(),
+            from_effect,
+            self.level,
+            duration,
+        ));
+    }
+
+    /// Blinks the light for `duration`. Anything set before the next frame is what
+    /// blinks, the current mode and brightness come back afterwards.
+    pub fn begin_flash(&mut self, duration: Duration) {
+        self.end_flash();
+        self.flash = Some(Flash {
+            mode: self.mode.clone(),
+            brightness: self.brightness,
+            start: Instant::now(),
+            duration,
+        });
+    }
+
+    pub fn end_flash(&mut self) {
+        if let Some(flash) = self.flash.take() {
+            self.mode = flash.mode;
+            self.brightness = flash.brightness;
+        }
+    }
+
+    /// Leaves out a running flash, it is over by the time the state is restored.
+    pub fn state(&self) -> LightState {
+        let (mode, brightness) = match &self.flash {
+            Some(flash) => (flash.mode.clone(), flash.brightness),
+            None => (self.mode.clone(), self.brightness),
+        };
+        LightState {
+            mode,
+            brightness,
+            previous_mode: self.previous_mode.clone(),
+            previous_brightness: self.previous_brightness,
         }
     }
 
+    pub fn restore(&mut self, state: LightState) {
+        self.flash = None;
+        self.transition = None;
+        self.mode = state.mode;
+        self.brightness = state.brightness.clamp(0.0, 1.0);
+        self.previous_mode = state.previous_mode;
+        self.previous_brightness = state.previous_brightness.clamp(0.0, 1.0);
+    }
+
+    /// The brightness to show the last rendered frame at.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
     pub fn turn_off(&mut self) {
         if self.mode != RunMode::Off {
             self.previous_mode = self.mode.clone();
@@ -59,43 +177,76 @@ impl MyStrip {
         self.brightness = brightness;
     }
 
-    pub fn _set_hs(&mut self, h: f32, s: f32) {
-        self.mode = RunMode::Static(h, s);
+    /// Hue in degrees, saturation from 0 to 1.
+    pub fn set_hs(&mut self, h: f32, s: f32) {
+        self.mode = RunMode::Hs(h, s);
+    }
+
+    pub fn set_xy(&mut self, x: f32, y: f32) {
+        self.mode = RunMode::Xy(x, y);
     }
 
+    /// The colour as given, brightness stays separate.
     pub fn set_rgb(&mut self, r: u8, g: u8, b: u8) {
-        let srgb: Srgb<u8> = Srgb::<u8>::new(r, g, b);
-        let hsv = Hsv::from_color(srgb.into_format());
-        self.brightness = hsv.value;
-        self.mode = RunMode::Static(hsv.hue.into_inner(), hsv.saturation);
+        self.mode = RunMode::Rgb(r, g, b);
+    }
+
+    pub fn set_rgbw(&mut self, r: u8, g: u8, b: u8, w: u8) {
+        self.mode = RunMode::Rgbw(r, g, b, w);
     }
 
     pub fn _get_hsv(&self) -> Option<Hsv<u8>> {
         match self.mode {
-            RunMode::Static(h, s) => {
+            RunMode::Hs(h, s) => {
                 let hsv = Hsv::new(h, s, self.brightness);
                 Some(hsv)
             }
+            RunMode::Rgb(..) | RunMode::Xy(..) => {
+                let rgb = Self::colour(&self.mode)?;
+                let srgb = Srgb::new(rgb.red, rgb.green, rgb.blue).into_format::<f32>();
+                let hsv = Hsv::from_color(srgb);
+                Some(Hsv::new(hsv.hue, hsv.saturation, self.brightness))
+            }
+            RunMode::Rgbw(..) => None,
+            RunMode::Temperature(_) => None,
             RunMode::Dynamic(_) => None,
             RunMode::Off => None,
         }
     }
 
-    pub fn _set_temperature(&mut self, mired: u64) {
-        let kelvin = 1000000 / mired as i64;
-        let rgb: colortemp::RGB = colortemp::temp_to_rgb(kelvin);
-        self.set_rgb(rgb.r as u8, rgb.g as u8, rgb.b as u8);
+    pub fn set_temperature(&mut self, mireds: u16) {
+        self.mode = RunMode::Temperature(mireds.clamp(MIN_MIREDS, MAX_MIREDS));
     }
 
-    pub fn get_rgb(&self) -> Option<(u8, u8, u8)> {
-        match self.mode {
-            RunMode::Static(h, s) => {
-                let hsv = Hsv::new(h, s, self.brightness);
-                let srgb = Srgb::from_color(hsv).into_format::<u8>();
-                Some((srgb.red, srgb.green, srgb.blue))
+    /// The colour without brightness applied.
+    pub fn _get_rgb(&self) -> Option<(u8, u8, u8)> {
+        let rgb = Self::colour(&self.mode)?;
+        Some((rgb.red, rgb.green, rgb.blue))
+    }
+
+    /// Static colours at full brightness.
+    fn colour(mode: &RunMode) -> Option<Rgb> {
+        match *mode {
+            RunMode::Rgb(r, g, b) => Some(Rgb::new(r, g, b)),
+            RunMode::Hs(h, s) => {
+                let srgb = Srgb::from_color(Hsv::new(h, s, 1.0)).into_format::<u8>();
+                Some(Rgb::new(srgb.red, srgb.green, srgb.blue))
             }
-            RunMode::Dynamic(_) => None,
-            RunMode::Off => None,
+            RunMode::Xy(x, y) => {
+                // The most saturated colour the LEDs can make at that chromaticity.
+                let linear = LinSrgb::from_color(Yxy::new(x, y, 1.0));
+                let max = linear.red.max(linear.green).max(linear.blue);
+                let [red, green, blue] =
+                    [linear.red, linear.green, linear.blue].map(|c| (c / max).max(0.0));
+                let srgb: Srgb<u8> = Srgb::from_linear(LinSrgb::new(red, green, blue));
+                Some(Rgb::new(srgb.red, srgb.green, srgb.blue))
+            }
+            RunMode::Temperature(mireds) => {
+                let kelvin = 1_000_000 / mireds as i64;
+                let rgb = colortemp::temp_to_rgb(kelvin);
+                Some(Rgb::new(rgb.r as u8, rgb.g as u8, rgb.b as u8))
+            }
+            RunMode::Rgbw(..) | RunMode::Dynamic(_) | RunMode::Off => None,
         }
     }
 
@@ -109,23 +260,66 @@ impl MyStrip {
         }
     }
 
-    pub fn set_effect(&mut self, effect: &str) {
+    pub fn has_effect(&self, effect: &str) -> bool {
+        self.effects_map.contains_key(effect)
+    }
+
+    /// Leaves the mode alone if there's no effect by that name.
+    pub fn set_effect(&mut self, effect: &str) -> Result<(), String> {
+        if !self.has_effect(effect) {
+            return Err(format!("Unknown effect {}", effect));
+        }
         log::debug!("Setting effect: {}", effect);
         self.mode = RunMode::Dynamic(effect.to_string());
+        Ok(())
     }
 
     pub fn _list_effects(&self) -> Vec<String> {
         self.effects_map.keys().cloned().collect()
     }
 
+    /// Reports the mode a flash will go back to, not the one blinking.
     pub fn state_message(&self) -> String {
-        let brightness = (self.brightness * 255.0) as u8;
-        match &self.mode {
-            RunMode::Static(_h, _s) => {
-                let rgb = self.get_rgb().unwrap();
+        let (mode, level) = match &self.flash {
+            Some(flash) => (&flash.mode, flash.brightness),
+            None => (&self.mode, self.brightness),
+        };
+        let brightness = (level * 255.0) as u8;
+        match mode {
+            RunMode::Rgb(r, g, b) => {
                 let payload = format!(
-                    "{{\"state\": \"ON\", \"brightness\": {}, \"color\": {{\"r\": {}, \"g\": {}, \"b\": {}}}}}",
-                    brightness, rgb.0, rgb.1, rgb.2
+                    "{{\"state\": \"ON\", \"brightness\": {}, \"color_mode\": \"rgb\", \"color\": {{\"r\": {}, \"g\": {}, \"b\": {}}}}}",
+                    brightness, r, g, b
+                );
+                payload
+            }
+            RunMode::Hs(h, s) => {
+                let payload = format!(
+                    "{{\"state\": \"ON\", \"brightness\": {}, \"color_mode\": \"hs\", \"color\": {{\"h\": {}, \"s\": {}}}}}",
+                    brightness,
+                    h,
+                    s * 100.0
+                );
+                payload
+            }
+            RunMode::Xy(x, y) => {
+                let payload = format!(
+                    "{{\"state\": \"ON\", \"brightness\": {}, \"color_mode\": \"xy\", \"color\": {{\"x\": {}, \"y\": {}}}}}",
+                    brightness, x, y
+                );
+                payload
+            }
+            RunMode::Rgbw(r, g, b, w) => {
+                let payload = format!(
+                    "{{\"state\": \"ON\", \"brightness\": {}, \"color_mode\": \"rgbw\", \"color\": {{\"r\": {}, \"g\": {}, \"b\": {}, \"w\": {}}}}}",
+                    brightness, r, g, b, w
+                );
+                payload
+            }
+            RunMode::Temperature(mireds) => {
+                let payload = format!(
+                    "{{\"state\": \"ON\", \"brightness\": {}, \"color_mode\": \"color_temp\", \"color_temp\": {}}}",
+                    brightness, mireds
                 );
                 payload
             }
@@ -140,39 +334,122 @@ impl MyStrip {
         }
     }
 
-    pub fn update(&mut self) {
-        match &self.mode {
-            RunMode::Static(h, s) => {
-                let hsv = Hsv::new(*h, *s, self.brightness);
-                let srgb = Srgb::from_color(hsv).into_format::<u8>();
-                let rgb = Rgb::new(srgb.red, srgb.green, srgb.blue);
-                let _ = self.strip.clear(0);
-                let _ = self.strip.fill(0, &rgb);
+    /// The next frame at full brightness, or `None` to keep showing the last one.
+    pub fn render(&mut self) -> Option<Vec<Rgbw>> {
+        let mut flashed = false;
+        if let Some(flash) = &self.flash {
+            let elapsed = flash.start.elapsed();
+            if elapsed < flash.duration {
+                return Some(self.render_flash(elapsed));
             }
-            RunMode::Dynamic(effect_name) => {
-                if let Some(effect) = self.effects_map.get_mut(effect_name) {
-                    let pixels = effect.next();
-
-                    if let Some(mut pixels) = pixels {
-                        if 1.0 != self.brightness {
-                            pixels.iter_mut().for_each(|x| {
-                                let mut srgb: Srgb<f32> = x.into_format();
-                                srgb = srgb.darken(1.0 - self.brightness);
-                                *x = srgb.into_format();
-                            });
-                        }
-                        let pixels = pixels
-                            .iter()
-                            .map(|x| Rgb::new(x.red, x.green, x.blue))
-                            .collect::<Vec<Rgb>>();
-                        self.strip.set_page(0, pixels).expect("Error setting page");
-                    }
-                }
+            self.end_flash();
+            flashed = true;
+        }
+
+        // Effects may not have a frame ready, don't leave the flash showing.
+        let frame = match self.render_mode() {
+            None if flashed => Some(self.frame.clone()),
+            frame => frame,
+        };
+
+        let Some(mut transition) = self.transition.take() else {
+            self.level = self.brightness;
+            if let Some(frame) = &frame {
+                self.frame.clone_from(frame);
+                self.displayed.clone_from(frame);
             }
-            RunMode::Off => {
-                let _ = self.strip.clear(0);
+            return frame;
+        };
+
+        if let Some(frame) = frame {
+            self.frame = frame;
+        }
+        if let Some(effect) = &transition.from_effect {
+            if self.mode == RunMode::Dynamic(effect.clone()) {
+                // Only the brightness changes, the effect runs once.
+                transition.from.clone_from(&self.frame);
+            } else if let Some(from) = self.render_effect(effect) {
+                transition.from = from;
             }
         }
-        self.strip.refresh(0).expect("Error displaying LED");
+
+        let progress = transition.progress();
+        self.level = transition.brightness(self.brightness, progress);
+        self.displayed = transition.mix(&self.frame, progress);
+        if progress < 1.0 {
+            self.transition = Some(transition);
+        }
+        Some(self.displayed.clone())
+    }
+
+    fn render_flash(&mut self, elapsed: Duration) -> Vec<Rgbw> {
+        self.level = self.brightness;
+        let lit = elapsed.as_millis() % FLASH_PERIOD.as_millis() < FLASH_PERIOD.as_millis() / 2;
+        if !lit {
+            return vec![Rgbw::new(0, 0, 0, 0); self.count];
+        }
+        match self.mode {
+            RunMode::Off => vec![Rgbw::new(255, 255, 255, 0); self.count],
+            _ => self
+                .render_mode()
+                .unwrap_or_else(|| vec![Rgbw::new(255, 255, 255, 0); self.count]),
+        }
+    }
+
+    fn render_mode(&mut self) -> Option<Vec<Rgbw>> {
+        match &self.mode {
+            RunMode::Rgb(..) | RunMode::Hs(..) | RunMode::Xy(..) | RunMode::Temperature(_) => {
+                let rgb = Self::colour(&self.mode)?;
+                let rgbw = self.white.apply(&[rgb]).remove(0);
+                Some(vec![rgbw; self.count])
+            }
+            RunMode::Rgbw(r, g, b, w) => Some(vec![Rgbw::new(*r, *g, *b, *w); self.count]),
+            RunMode::Dynamic(effect_name) => self.render_effect(&effect_name.clone()),
+            RunMode::Off => Some(vec![Rgbw::new(0, 0, 0, 0); self.count]),
+        }
+    }
+
+    fn render_effect(&mut self, name: &str) -> Option<Vec<Rgbw>> {
+        let effect = self.effects_map.get_mut(name)?;
+        let pixels = effect
+            .next()?
+            .iter()
+            .map(|x| Rgb::new(x.red, x.green, x.blue))
+            .collect::<Vec<Rgb>>();
+        Some(self.white.apply(&pixels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn flash_restores_the_mode_and_brightness() {
+        let mut light = MyStrip::new(3, WhiteExtraction::None, Duration::ZERO);
+        light.set_rgb(0, 0, 255);
+        light.set_brightness(0.5);
+        assert_eq!(light.render(), Some(vec![Rgbw::new(0, 0, 255, 0); 3]));
+        let before = light.state_message();
+
+        // Lit for the first half of each second, so this is on, then off.
+        light.begin_flash(Duration::from_millis(800));
+        light.set_rgb(255, 0, 0);
+        light.set_brightness(1.0);
+        assert_eq!(light.render(), Some(vec![Rgbw::new(255, 0, 0, 0); 3]));
+        assert_eq!(light.level(), 1.0);
+        assert_eq!(light.state_message(), before);
+
+        sleep(Duration::from_millis(600));
+        assert_eq!(light.render(), Some(vec![Rgbw::new(0, 0, 0, 0); 3]));
+        assert_eq!(light.state_message(), before);
+
+        sleep(Duration::from_millis(250));
+        assert_eq!(light.render(), Some(vec![Rgbw::new(0, 0, 255, 0); 3]));
+        assert_eq!(light.mode, RunMode::Rgb(0, 0, 255));
+        assert_eq!(light.brightness, 0.5);
+        assert_eq!(light.level(), 0.5);
+        assert_eq!(light.state_message(), before);
     }
 }
@@ -0,0 +1,172 @@
+use crate::ws2812::Ws2812Error;
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::fs;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Result<T> = std::result::Result<T, Ws2812Error>;
+
+const RECORDED_FRAMES: usize = 100;
+const SPIDEV_BUFSIZ: &str = "/sys/module/spidev/parameters/bufsiz";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub name: &'static str,
+    pub clock_hz: u32,
+    /// Largest single transfer, longer writes are split.
+    pub max_transfer: Option<usize>,
+}
+
+/// The kernel's limit on a single spidev transfer, if the module exposes it.
+pub fn spidev_bufsiz() -> Option<usize> {
+    fs::read_to_string(SPIDEV_BUFSIZ)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Where encoded frames end up. `Strip` only ever talks to the LEDs through this.
+pub trait Output: Send {
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+    /// Marks the end of a frame. `Strip` encodes the latch itself, so this only
+    /// matters to backends that need to know where frames start and end.
+    fn reset(&mut self) -> Result<()>;
+    fn capabilities(&self) -> Capabilities;
+}
+
+pub struct SpiOutput {
+    spi: Spi,
+    clock_hz: u32,
+    max_transfer: usize,
+}
+
+impl SpiOutput {
+    pub fn new(bus: Bus, ss: SlaveSelect, clock_hz: u32, max_transfer: usize) -> Result<SpiOutput> {
+        let spi = Spi::new(bus, ss, clock_hz, Mode::Mode0)
+            .map_err(|e| Ws2812Error::SpiError(format!("Error opening SPI: {}", e)))?;
+        Ok(SpiOutput {
+            spi,
+            clock_hz,
+            max_transfer,
+        })
+    }
+}
+
+impl Output for SpiOutput {
+    /// spidev caps a whole message at bufsiz, `transfer_segments` included, so a
+    /// longer frame has to go out as back to back writes. The gap between them is
+    /// short, but not guaranteed to be shorter than a WS2812 latch.
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(self.max_transfer) {
+            self.spi.write(chunk).map_err(|e| {
+                Ws2812Error::SpiError(format!("Error writing {} bytes to SPI: {}", chunk.len(), e))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: "spi",
+            clock_hz: self.clock_hz,
+            max_transfer: Some(self.max_transfer),
+        }
+    }
+}
+
+/// Keeps the most recent frames in memory instead of driving hardware. Clones
+/// share the recording, so one can be kept to look at what a `Strip` wrote.
+#[derive(Clone, Default)]
+pub struct RecordingOutput {
+    recording: Arc<Mutex<Recording>>,
+}
+
+#[derive(Default)]
+struct Recording {
+    pending: Vec<u8>,
+    frames: Vec<Vec<u8>>,
+    latched: usize,
+}
+
+impl RecordingOutput {
+    pub fn new() -> RecordingOutput {
+        RecordingOutput::default()
+    }
+
+    fn recording(&self) -> MutexGuard<'_, Recording> {
+        self.recording.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        self.recording().frames.clone()
+    }
+
+    pub fn latched(&self) -> usize {
+        self.recording().latched
+    }
+}
+
+impl Output for RecordingOutput {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.recording().pending.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let mut recording = self.recording();
+        if recording.frames.len() == RECORDED_FRAMES {
+            recording.frames.remove(0);
+        }
+        let frame = std::mem::take(&mut recording.pending);
+        log::trace!(
+            "Recorded frame {} ({} bytes)",
+            recording.latched + 1,
+            frame.len()
+        );
+        recording.frames.push(frame);
+        recording.latched += 1;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: "recording",
+            clock_hz: 0,
+            max_transfer: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws2812::{ChannelOrder, Chip, Encoder, Encoding, Rgbw, Strip};
+
+    #[test]
+    fn records_each_refreshed_frame() {
+        let output = RecordingOutput::new();
+        let mut strip = Strip::new(
+            Box::new(output.clone()),
+            2,
+            1,
+            ChannelOrder::Grb,
+            Chip::Ws2812,
+            Encoding::Spi24,
+            false,
+        )
+        .unwrap();
+        strip
+            .set_led(0, 0, &Rgbw::new(0xFF, 0x00, 0xA5, 0))
+            .unwrap();
+        strip.set_led(0, 1, &Rgbw::new(1, 2, 3, 0)).unwrap();
+        strip.refresh(0).unwrap();
+
+        let mut expected = Vec::new();
+        Encoder::new(Encoding::Spi24).extend(&mut expected, &[0x00, 0xFF, 0xA5, 2, 1, 3]);
+        expected.resize(expected.len() + Encoding::Spi24.latch_len(), 0);
+        assert_eq!(output.latched(), 1);
+        assert_eq!(output.frames(), [expected]);
+    }
+}
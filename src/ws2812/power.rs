@@ -0,0 +1,84 @@
+/// Estimates what a frame draws from the supply and how far it has to be dimmed
+/// to stay within it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerBudget {
+    /// `None` only estimates, without limiting.
+    pub supply_ma: Option<f32>,
+    /// Current of one channel at full duty.
+    pub channel_ma: f32,
+    /// Quiescent current of each LED, even when dark.
+    pub idle_ma: f32,
+}
+
+impl Default for PowerBudget {
+    fn default() -> Self {
+        PowerBudget {
+            supply_ma: None,
+            channel_ma: 20.0,
+            idle_ma: 1.0,
+        }
+    }
+}
+
+impl PowerBudget {
+    /// `frame` holds channels on a 0.0..=255.0 scale, before each LED's level is applied.
+    pub fn estimate(&self, frame: &[[f32; 4]], levels: &[f32]) -> f32 {
+        let duty: f32 = frame
+            .iter()
+            .zip(levels)
+            .map(|(channels, level)| channels.iter().sum::<f32>() * level)
+            .sum::<f32>()
+            / 255.0;
+        self.idle_ma * frame.len() as f32 + duty * self.channel_ma
+    }
+
+    /// Factor to scale the frame by so the estimate stays under the supply.
+    pub fn limit(&self, estimate: f32, count: usize) -> f32 {
+        let Some(supply_ma) = self.supply_ma else {
+            return 1.0;
+        };
+        if estimate <= supply_ma {
+            return 1.0;
+        }
+
+        let idle = self.idle_ma * count as f32;
+        let lit = estimate - idle;
+        // A dark frame only draws idle current, dimming it wouldn't help.
+        if lit <= 0.0 {
+            return 1.0;
+        }
+        let available = (supply_ma - idle).max(0.0);
+        available / lit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(supply_ma: f32) -> PowerBudget {
+        PowerBudget {
+            supply_ma: Some(supply_ma),
+            ..PowerBudget::default()
+        }
+    }
+
+    #[test]
+    fn scales_the_lit_part_of_the_frame() {
+        let budget = budget(510.0);
+        let frame = vec![[255.0; 4]; 10];
+        let estimate = budget.estimate(&frame, &[1.0; 10]);
+        assert_eq!(estimate, 810.0);
+        let limit = budget.limit(estimate, 10);
+        assert_eq!(limit, 0.625);
+        assert_eq!(budget.estimate(&frame, &[limit; 10]), 510.0);
+    }
+
+    #[test]
+    fn dark_frames_over_the_supply_are_left_alone() {
+        let budget = budget(5.0);
+        let frame = vec![[0.0; 4]; 10];
+        let estimate = budget.estimate(&frame, &[1.0; 10]);
+        assert_eq!(budget.limit(estimate, 10), 1.0);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::ws2812::{ChannelOrder, Rgb};
+use std::fmt::Display;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rgbw {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub white: u8,
+}
+
+impl Display for Rgbw {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "(R: {}, G: {}, B: {}, W: {})",
+            self.red, self.green, self.blue, self.white
+        )
+    }
+}
+
+impl From<Rgb> for Rgbw {
+    fn from(rgb: Rgb) -> Self {
+        Rgbw::new(rgb.red, rgb.green, rgb.blue, 0)
+    }
+}
+
+impl From<&Rgbw> for Rgb {
+    /// Folds the white channel back into RGB for chips without a white LED.
+    fn from(rgbw: &Rgbw) -> Self {
+        Rgb::new(
+            rgbw.red.saturating_add(rgbw.white),
+            rgbw.green.saturating_add(rgbw.white),
+            rgbw.blue.saturating_add(rgbw.white),
+        )
+    }
+}
+
+impl Rgbw {
+    pub fn new(red: u8, green: u8, blue: u8, white: u8) -> Rgbw {
+        Rgbw {
+            red,
+            green,
+            blue,
+            white,
+        }
+    }
+
+    pub fn to_array(&self, order: ChannelOrder) -> [u8; 4] {
+        let [a, b, c] = order.arrange(self.red, self.green, self.blue);
+        [a, b, c, self.white]
+    }
+}
@@ -0,0 +1,120 @@
+use crate::ws2812::{MyStrip, Rgbw, Strip, WhiteExtraction};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// A run of LEDs on the physical strip that behaves as a light of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+    pub name: String,
+    pub start: usize,
+    pub length: usize,
+    #[serde(default)]
+    pub reversed: bool,
+}
+
+impl Segment {
+    pub fn end(&self) -> usize {
+        self.start + self.length
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}..{}{})",
+            self.name,
+            self.start,
+            self.end(),
+            if self.reversed { ", reversed" } else { "" }
+        )
+    }
+}
+
+/// Renders every segment's light and composes them into one frame on the strip.
+pub struct SegmentedStrip {
+    strip: Strip,
+    segments: Vec<Segment>,
+    lights: Vec<MyStrip>,
+    frames: Vec<Vec<Rgbw>>,
+}
+
+impl SegmentedStrip {
+    pub fn new(
+        strip: Strip,
+        segments: Vec<Segment>,
+        white: WhiteExtraction,
+        transition: Duration,
+    ) -> Self {
+        let white = if strip.has_white() {
+            white
+        } else {
+            WhiteExtraction::None
+        };
+
+        let lights = segments
+            .iter()
+            .map(|segment| MyStrip::new(segment.length, white, transition))
+            .collect();
+        let frames = segments
+            .iter()
+            .map(|segment| vec![Rgbw::new(0, 0, 0, 0); segment.length])
+            .collect();
+
+        for segment in &segments {
+            log::info!("Segment {}", segment);
+        }
+
+        SegmentedStrip {
+            strip,
+            segments,
+            lights,
+            frames,
+        }
+    }
+
+    pub fn _segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn light(&self, index: usize) -> &MyStrip {
+        &self.lights[index]
+    }
+
+    pub fn light_mut(&mut self, index: usize) -> &mut MyStrip {
+        &mut self.lights[index]
+    }
+
+    pub fn current_draw(&self) -> f32 {
+        self.strip.current_draw()
+    }
+
+    /// Turns every LED off without touching the lights' modes.
+    pub fn blank(&mut self) {
+        let page = self.strip.back_page();
+        let cleared = self.strip.clear(page).and_then(|_| self.strip.swap());
+        if let Err(e) = cleared {
+            log::error!("Error blanking strip: {}", e);
+        }
+    }
+
+    pub fn update(&mut self) {
+        let page = self.strip.back_page();
+        for (i, segment) in self.segments.iter().enumerate() {
+            let light = &mut self.lights[i];
+            if let Some(frame) = light.render() {
+                self.frames[i] = frame;
+            }
+
+            self.strip
+                .set_range(page, segment.start, &self.frames[i], segment.reversed)
+                .expect("Error setting segment");
+            self.strip
+                .set_brightness(segment.start..segment.end(), light.level())
+                .expect("Error setting segment brightness");
+        }
+        self.strip.swap().expect("Error displaying LED");
+    }
+}
@@ -1,46 +1,165 @@
-use crate::ws2812::rgb::Rgb;
-use crate::ws2812::Ws2812Error;
-use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use crate::ws2812::apa102;
+use crate::ws2812::{
+    Calibration, ChannelOrder, Chip, Dither, Encoder, Encoding, Output, PowerBudget, Rgb, Rgbw,
+    Ws2812Error,
+};
+use std::ops::Range;
 
 pub struct Strip {
-    pub spi: Spi,
+    output: Box<dyn Output>,
     pub count: usize,
-    pages: Vec<Vec<Rgb>>,
+    order: ChannelOrder,
+    chip: Chip,
+    brightness: Vec<f32>,
+    calibration: Calibration,
+    dither: Dither,
+    power: PowerBudget,
+    current_ma: f32,
+    encoder: Encoder,
+    frame: Vec<[f32; 4]>,
+    levels: Vec<f32>,
+    buffer: Vec<u8>,
+    latch: Vec<u8>,
+    pages: Vec<Vec<Rgbw>>,
     current_page: usize,
 }
 
+pub const MAX_LEDS: usize = 1024;
+
 type Result<T> = std::result::Result<T, Ws2812Error>;
 impl Strip {
-    pub fn new(bus: Bus, ss: SlaveSelect, count: usize, pages: usize) -> Result<Strip> {
-        if count > 1024 {
+    pub fn new(
+        output: Box<dyn Output>,
+        count: usize,
+        pages: usize,
+        order: ChannelOrder,
+        chip: Chip,
+        encoding: Encoding,
+        split_frames: bool,
+    ) -> Result<Strip> {
+        if count > MAX_LEDS {
             return Err(Ws2812Error::LedOutOfRange(format!(
                 "Led count {} is out of range",
                 count
             )));
         }
 
+        let capabilities = output.capabilities();
+        log::info!(
+            "Driving {} {} {} LEDs through {} output at {} Hz",
+            count,
+            chip,
+            order,
+            capabilities.name,
+            capabilities.clock_hz
+        );
+        if !chip.is_clocked() {
+            log::info!("Using {} encoding", encoding);
+        }
+
+        let frame_len = Self::frame_len(chip, count, encoding);
+        if let Some(max_transfer) = capabilities.max_transfer {
+            if frame_len > max_transfer {
+                // Clocked chips don't care about gaps, timing based ones may latch early.
+                if !chip.is_clocked() && !split_frames {
+                    return Err(Ws2812Error::FrameTooLarge(format!(
+                        "{} byte frame doesn't fit in one {} byte SPI transfer, raise spidev.bufsiz, use a narrower encoding or enable splitFrames",
+                        frame_len, max_transfer
+                    )));
+                }
+                log::warn!(
+                    "Sending {} byte frames in {} transfers of up to {} bytes",
+                    frame_len,
+                    frame_len.div_ceil(max_transfer),
+                    max_transfer
+                );
+            }
+        }
+
+        let encoder = Encoder::new(encoding);
+        let buffer = Vec::with_capacity(frame_len);
+        let latch = if chip.is_clocked() {
+            Vec::new()
+        } else {
+            vec![0; encoding.latch_len()]
+        };
+
         Ok(Strip {
             count,
-            spi: Spi::new(bus, ss, 32_000_000, Mode::Mode0).unwrap(),
-            pages: vec![Vec::with_capacity(count); pages],
+            output,
+            order,
+            chip,
+            brightness: vec![1.0; count],
+            calibration: Calibration::default(),
+            dither: Dither::default(),
+            power: PowerBudget::default(),
+            current_ma: 0.0,
+            encoder,
+            frame: Vec::with_capacity(count),
+            levels: Vec::with_capacity(count),
+            buffer,
+            latch,
+            pages: vec![vec![Rgbw::new(0, 0, 0, 0); count]; pages],
             current_page: 0,
         })
     }
 
-    pub fn fill(&mut self, page: usize, rgb: &Rgb) -> Result<()> {
+    /// Bytes in one write of a frame, the WS2812 latch is written separately.
+    fn frame_len(chip: Chip, count: usize, encoding: Encoding) -> usize {
+        if chip.is_clocked() {
+            apa102::START_FRAME.len() + count * chip.channels() + apa102::end_frame_len(count)
+        } else {
+            encoding.frame_len(count, chip.channels())
+        }
+    }
+
+    pub fn has_white(&self) -> bool {
+        self.chip.has_white()
+    }
+
+    /// Applied when the frame is encoded; clocked chips use their hardware brightness field.
+    pub fn set_brightness(&mut self, leds: Range<usize>, brightness: f32) -> Result<()> {
+        if leds.end > self.count {
+            return Err(Ws2812Error::LedOutOfRange(format!(
+                "Leds {:?} are out of range",
+                leds
+            )));
+        }
+        self.brightness[leds].fill(brightness.clamp(0.0, 1.0));
+        Ok(())
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn set_dithering(&mut self, enabled: bool) {
+        self.dither = Dither::new(enabled);
+    }
+
+    pub fn set_power_budget(&mut self, power: PowerBudget) {
+        self.power = power;
+    }
+
+    /// Estimated draw of the last refreshed frame, in mA, after limiting.
+    pub fn current_draw(&self) -> f32 {
+        self.current_ma
+    }
+
+    pub fn fill(&mut self, page: usize, rgb: &Rgbw) -> Result<()> {
         if self.pages.len() <= page {
             return Err(Ws2812Error::PageOutOfRange(format!(
                 "Page {} is out of range",
                 page
             )));
         }
-        self.pages[page] = Vec::new();
-        self.pages[page].extend_from_slice(&vec![rgb.clone(); self.count]);
-        self.set_led(page, 0, &Rgb::new(0, 0, 0))?;
+        self.pages[page].clear();
+        self.pages[page].resize(self.count, rgb.clone());
+        self.set_led(page, 0, &Rgbw::new(0, 0, 0, 0))?;
         Ok(())
     }
 
-    pub fn set_page(&mut self, page: usize, pixels: Vec<Rgb>) -> Result<()> {
+    pub fn _set_page(&mut self, page: usize, pixels: Vec<Rgbw>) -> Result<()> {
         if self.pages.len() <= page {
             return Err(Ws2812Error::PageOutOfRange(format!(
                 "Page {} is out of range",
@@ -58,7 +177,40 @@ impl Strip {
         Ok(())
     }
 
-    pub fn set_led(&mut self, page: usize, led: usize, rgb: &Rgb) -> Result<()> {
+    /// Copies `pixels` into the page starting at `start`, reversed if asked.
+    pub fn set_range(
+        &mut self,
+        page: usize,
+        start: usize,
+        pixels: &[Rgbw],
+        reversed: bool,
+    ) -> Result<()> {
+        if self.pages.len() <= page {
+            return Err(Ws2812Error::PageOutOfRange(format!(
+                "Page {} is out of range",
+                page
+            )));
+        }
+        if start + pixels.len() > self.count {
+            return Err(Ws2812Error::LedOutOfRange(format!(
+                "Leds {}..{} are out of range",
+                start,
+                start + pixels.len()
+            )));
+        }
+
+        let leds = &mut self.pages[page][start..start + pixels.len()];
+        if reversed {
+            leds.iter_mut()
+                .zip(pixels.iter().rev())
+                .for_each(|(led, p)| *led = p.clone());
+        } else {
+            leds.clone_from_slice(pixels);
+        }
+        Ok(())
+    }
+
+    pub fn set_led(&mut self, page: usize, led: usize, rgb: &Rgbw) -> Result<()> {
         if self.pages.len() <= page {
             return Err(Ws2812Error::PageOutOfRange(format!(
                 "Page {} is out of range",
@@ -83,25 +235,126 @@ impl Strip {
                 page
             )));
         }
-        let mut buffer = Vec::new();
-        self.pages[page]
-            .iter()
-            .for_each(|led| buffer.extend_from_slice(&led.to_spi_data()));
+        let has_white = self.chip.has_white();
+        let calibration = &self.calibration;
+        self.frame.clear();
+        self.frame.extend(self.pages[page].iter().map(|led| {
+            if has_white {
+                calibration.apply(led)
+            } else {
+                calibration.apply(&Rgbw::from(Rgb::from(led)))
+            }
+        }));
 
-        if let Err(e) = self.spi.write(&buffer) {
-            Err(Ws2812Error::SpiError(format!(
-                "Error writing to SPI: {}",
-                e
-            )))
-        } else {
-            let buffer = vec![0u8; 200];
-            self.spi.write(&buffer).unwrap();
-            self.current_page = page;
-            Ok(())
+        self.levels.clear();
+        self.levels
+            .extend(self.brightness.iter().map(|b| calibration.level(*b)));
+
+        let estimate = self.power.estimate(&self.frame, &self.levels);
+        let limit = self.power.limit(estimate, self.count);
+        if limit < 1.0 {
+            log::debug!("Limiting {:.0} mA frame by {:.2}", estimate, limit);
+            self.levels.iter_mut().for_each(|level| *level *= limit);
+        }
+        self.current_ma = self.power.estimate(&self.frame, &self.levels);
+
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        if self.chip.is_clocked() {
+            buffer.extend_from_slice(&apa102::START_FRAME);
+        }
+        for (i, (channels, level)) in self.frame.iter().zip(&self.levels).enumerate() {
+            let (global, scale) = if self.chip.is_clocked() {
+                apa102::global_brightness(*level)
+            } else {
+                (0, *level)
+            };
+            let channels = channels.map(|c| c * scale);
+            let [red, green, blue, white] = self.dither.quantise(i, channels);
+            let led = Rgbw::new(red, green, blue, white);
+            match self.chip {
+                Chip::Ws2812 => {
+                    let rgb = Rgb::from(&led);
+                    self.encoder.extend(buffer, &rgb.to_array(self.order));
+                }
+                Chip::Sk6812Rgbw => {
+                    self.encoder.extend(buffer, &led.to_array(self.order));
+                }
+                Chip::Apa102 | Chip::Sk9822 => {
+                    let rgb = Rgb::from(&led);
+                    buffer.extend_from_slice(&apa102::led_frame(&rgb, self.order, global));
+                }
+            }
         }
+        if self.chip.is_clocked() {
+            buffer.resize(buffer.len() + apa102::end_frame_len(self.count), 0);
+        }
+
+        self.output.write(&self.buffer)?;
+        if !self.latch.is_empty() {
+            self.output.write(&self.latch)?;
+        }
+        self.output.reset()?;
+        self.current_page = page;
+        Ok(())
     }
 
     pub fn clear(&mut self, page: usize) -> Result<()> {
-        self.fill(page, &Rgb::new(0, 0, 0))
+        self.fill(page, &Rgbw::new(0, 0, 0, 0))
+    }
+
+    /// The page to render the next frame into. With a single page this is also
+    /// the one on display.
+    pub fn back_page(&self) -> usize {
+        (self.current_page + 1) % self.pages.len()
+    }
+
+    /// Shows the back page. The front page only changes once the frame is out.
+    pub fn swap(&mut self) -> Result<()> {
+        self.refresh(self.back_page())
+    }
+
+    /// The frame currently on the LEDs and the level each LED was sent at, after
+    /// brightness, gamma and power limiting. The levels are empty until the first
+    /// refresh.
+    pub fn front(&self) -> (&[Rgbw], &[f32]) {
+        (&self.pages[self.current_page], &self.levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws2812::RecordingOutput;
+
+    #[test]
+    fn front_is_what_was_sent() {
+        let mut strip = Strip::new(
+            Box::new(RecordingOutput::new()),
+            2,
+            2,
+            ChannelOrder::Grb,
+            Chip::Ws2812,
+            Encoding::Spi24,
+            false,
+        )
+        .unwrap();
+        strip.set_power_budget(PowerBudget {
+            supply_ma: Some(27.0),
+            channel_ma: 20.0,
+            idle_ma: 1.0,
+        });
+        strip.set_brightness(0..1, 0.5).unwrap();
+
+        let page = strip.back_page();
+        let pixels = [Rgbw::new(255, 0, 0, 0), Rgbw::new(0, 255, 255, 0)];
+        strip.set_range(page, 0, &pixels, false).unwrap();
+        strip.swap().unwrap();
+
+        // 10 mA and 40 mA lit on top of 2 mA idle, halved to fit 25 mA lit.
+        let (front, levels) = strip.front();
+        assert_eq!(front, pixels);
+        assert_eq!(levels, [0.25, 0.5]);
+        assert_eq!(strip.current_draw(), 27.0);
     }
 }
@@ -0,0 +1,57 @@
+use crate::ws2812::Rgbw;
+use palette::{Clamp, FromColor, Mix, Oklab, Srgb};
+use std::time::{Duration, Instant};
+
+/// A fade from what was on the light when a command arrived to whatever the
+/// light renders now.
+pub struct Transition {
+    pub from: Vec<Rgbw>,
+    /// Keeps animating the effect being faded out, unless it was interrupted mid fade.
+    pub from_effect: Option<String>,
+    pub from_brightness: f32,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Transition {
+    pub fn new(
+        from: Vec<Rgbw>,
+        from_effect: Option<String>,
+        from_brightness: f32,
+        duration: Duration,
+    ) -> Transition {
+        Transition {
+            from,
+            from_effect,
+            from_brightness,
+            start: Instant::now(),
+            duration,
+        }
+    }
+
+    /// How far along the fade is, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        (self.start.elapsed().as_secs_f32() / self.duration.as_secs_f32()).min(1.0)
+    }
+
+    pub fn brightness(&self, to: f32, progress: f32) -> f32 {
+        self.from_brightness + (to - self.from_brightness) * progress
+    }
+
+    /// Blends the frames in Oklab so the fade looks even, white is blended linearly.
+    pub fn mix(&self, to: &[Rgbw], progress: f32) -> Vec<Rgbw> {
+        self.from
+            .iter()
+            .zip(to)
+            .map(|(from, to)| {
+                let a = Oklab::from_color(Srgb::new(from.red, from.green, from.blue).into_format());
+                let b = Oklab::from_color(Srgb::new(to.red, to.green, to.blue).into_format());
+                let rgb = Srgb::from_color(a.mix(b, progress))
+                    .clamp()
+                    .into_format::<u8>();
+                let white = from.white as f32 + (to.white as f32 - from.white as f32) * progress;
+                Rgbw::new(rgb.red, rgb.green, rgb.blue, white.round() as u8)
+            })
+            .collect()
+    }
+}
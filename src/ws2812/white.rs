@@ -0,0 +1,119 @@
+use crate::ws2812::{Rgb, Rgbw};
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// White LEDs the colour temperature maths gives a sensible white point for.
+pub const KELVIN_RANGE: RangeInclusive<i64> = 1000..=40000;
+
+/// How much of an RGB colour is moved onto the white LED of an RGBW chip.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhiteExtraction {
+    /// Leave the white LED off.
+    #[default]
+    None,
+    /// Move the common part of R, G and B onto the white LED.
+    Min,
+    /// Like `Min`, but accounts for the white LED's colour temperature in kelvin.
+    Temperature(i64),
+}
+
+impl WhiteExtraction {
+    pub fn apply(&self, pixels: &[Rgb]) -> Vec<Rgbw> {
+        let white_point = match self {
+            WhiteExtraction::None => {
+                return pixels.iter().cloned().map(Rgbw::from).collect();
+            }
+            WhiteExtraction::Min => [1.0, 1.0, 1.0],
+            WhiteExtraction::Temperature(kelvin) => Self::white_point(*kelvin),
+        };
+
+        pixels
+            .iter()
+            .map(|p| Self::extract(p, &white_point))
+            .collect()
+    }
+
+    fn white_point(kelvin: i64) -> [f32; 3] {
+        let rgb = colortemp::temp_to_rgb(kelvin);
+        let max = rgb.r.max(rgb.g).max(rgb.b).max(1.0);
+        [
+            (rgb.r / max) as f32,
+            (rgb.g / max) as f32,
+            (rgb.b / max) as f32,
+        ]
+    }
+
+    fn extract(pixel: &Rgb, white_point: &[f32; 3]) -> Rgbw {
+        let channels = [pixel.red as f32, pixel.green as f32, pixel.blue as f32];
+
+        let white = channels
+            .iter()
+            .zip(white_point)
+            .filter(|(_, w)| **w > 0.0)
+            .map(|(c, w)| c / w)
+            .fold(255.0_f32, f32::min);
+
+        let [red, green, blue] = [0, 1, 2].map(|i| {
+            (channels[i] - white * white_point[i])
+                .round()
+                .clamp(0.0, 255.0) as u8
+        });
+
+        Rgbw::new(red, green, blue, white.round() as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(extraction: WhiteExtraction, rgb: Rgb) -> Rgbw {
+        extraction.apply(&[rgb]).remove(0)
+    }
+
+    #[test]
+    fn none_leaves_white_off() {
+        assert_eq!(
+            apply(WhiteExtraction::None, Rgb::new(100, 50, 20)),
+            Rgbw::new(100, 50, 20, 0)
+        );
+    }
+
+    #[test]
+    fn min_moves_the_common_part_to_white() {
+        assert_eq!(
+            apply(WhiteExtraction::Min, Rgb::new(100, 50, 20)),
+            Rgbw::new(80, 30, 0, 20)
+        );
+        assert_eq!(
+            apply(WhiteExtraction::Min, Rgb::new(255, 255, 255)),
+            Rgbw::new(0, 0, 0, 255)
+        );
+        assert_eq!(
+            apply(WhiteExtraction::Min, Rgb::new(255, 0, 0)),
+            Rgbw::new(255, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn temperature_moves_the_white_point_to_white() {
+        let extraction = WhiteExtraction::Temperature(2700);
+        let [r, g, b] = WhiteExtraction::white_point(2700).map(|c| (c * 200.0).round() as u8);
+        let rgbw = apply(extraction, Rgb::new(r, g, b));
+        assert!(
+            rgbw.red <= 1 && rgbw.green <= 1 && rgbw.blue <= 1,
+            "{}",
+            rgbw
+        );
+        assert!(rgbw.white >= 199, "{}", rgbw);
+
+        // A warm white LED can't make blue, so cool white keeps some of it.
+        let rgbw = apply(extraction, Rgb::new(200, 200, 200));
+        assert!(rgbw.blue > 0 && rgbw.white > 0, "{}", rgbw);
+        assert_eq!(
+            apply(extraction, Rgb::new(255, 0, 0)),
+            Rgbw::new(255, 0, 0, 0)
+        );
+    }
+}
@@ -1,6 +1,20 @@
+use std::fmt::{self, Display, Formatter};
+
 #[derive(Debug)]
 pub enum Ws2812Error {
     LedOutOfRange(String),
     SpiError(String),
     PageOutOfRange(String),
+    FrameTooLarge(String),
+}
+
+impl Display for Ws2812Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Ws2812Error::LedOutOfRange(e) => write!(f, "LED out of range: {}", e),
+            Ws2812Error::SpiError(e) => write!(f, "SPI error: {}", e),
+            Ws2812Error::PageOutOfRange(e) => write!(f, "Page out of range: {}", e),
+            Ws2812Error::FrameTooLarge(e) => write!(f, "Frame too large: {}", e),
+        }
+    }
 }